@@ -1,6 +1,6 @@
 // build.rs
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -14,6 +14,7 @@ fn main() {
     // set by cargo's artifact dependency feature, see
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_IRON_KERNEL_iron_kernel").unwrap());
+    let kernel = embed_symbols(&kernel, &PathBuf::from(std::env::var_os("OUT_DIR").unwrap()));
 
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
@@ -27,3 +28,198 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Size of the `.ksymtab` section the kernel reserves, see `kernel/src/symbols.rs`.
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+const SYMBOL_TABLE_MAGIC: &[u8] = b"IRONSYMS";
+const STT_FUNC: u8 = 2;
+
+/// Fills the kernel's `.ksymtab` section with its demangled function symbols
+/// and writes the result to `out_dir`, returning the path of the new kernel.
+fn embed_symbols(kernel: &Path, out_dir: &Path) -> PathBuf {
+    let mut elf = std::fs::read(kernel).unwrap();
+    let sections = sections(&elf);
+    let find = |name: &str| sections.iter().find(|section| section.name == name);
+    let table = find(".ksymtab").expect("the kernel has no .ksymtab section");
+    let (table_offset, table_addr) = (table.offset as usize, table.addr);
+    assert_eq!(table.size as usize, SYMBOL_TABLE_SIZE, ".ksymtab has an unexpected size");
+    assert_eq!(&elf[table_offset..table_offset + 8], SYMBOL_TABLE_MAGIC);
+
+    let mut symbols = Vec::new();
+    match find(".symtab") {
+        Some(symtab) => {
+            let strtab = &sections[symtab.link as usize];
+            let strings = &elf[strtab.offset as usize..(strtab.offset + strtab.size) as usize];
+            let entries = &elf[symtab.offset as usize..(symtab.offset + symtab.size) as usize];
+            for entry in entries.chunks_exact(24) {
+                let value = u64_at(entry, 8);
+                if entry[4] & 0xf != STT_FUNC || value == 0 {
+                    continue;
+                }
+                let name_start = u32_at(entry, 0) as usize;
+                let name_len = strings[name_start..].iter().position(|&b| b == 0).unwrap();
+                let name = String::from_utf8_lossy(&strings[name_start..name_start + name_len]);
+                let size = u64_at(entry, 16).min(u32::MAX as u64) as u32;
+                symbols.push((value, size, demangle(&name)));
+            }
+        }
+        None => println!("cargo:warning=the kernel has no symbol table, backtraces will not be symbolized"),
+    }
+    symbols.sort();
+    symbols.dedup_by_key(|symbol| symbol.0);
+
+    // drop the symbols at the highest addresses until the table fits
+    let table_size = |symbols: &[(u64, u32, String)]| {
+        24 + symbols.iter().map(|(_, _, name)| 16 + 2 + name.len().min(u16::MAX as usize)).sum::<usize>()
+    };
+    let all = symbols.len();
+    while table_size(&symbols) > SYMBOL_TABLE_SIZE {
+        symbols.pop();
+    }
+    if symbols.len() < all {
+        println!("cargo:warning=only {} of {} kernel symbols fit into .ksymtab", symbols.len(), all);
+    }
+
+    let names_offset = 24 + 16 * symbols.len();
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_SIZE);
+    let mut names = Vec::new();
+    table.extend_from_slice(SYMBOL_TABLE_MAGIC);
+    table.extend_from_slice(&table_addr.to_le_bytes());
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(names_offset as u32).to_le_bytes());
+    for (address, size, name) in &symbols {
+        let name = &name.as_bytes()[..name.len().min(u16::MAX as usize)];
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        names.extend_from_slice(name);
+    }
+    table.extend_from_slice(&names);
+    elf[table_offset..table_offset + table.len()].copy_from_slice(&table);
+
+    let path = out_dir.join("iron_kernel");
+    std::fs::write(&path, elf).unwrap();
+    path
+}
+
+/// A section header of the kernel ELF file.
+struct Section {
+    name: String,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+fn sections(elf: &[u8]) -> Vec<Section> {
+    let table = u64_at(elf, 0x28) as usize;
+    let entry_size = u16_at(elf, 0x3a) as usize;
+    let count = u16_at(elf, 0x3c) as usize;
+    let names_index = u16_at(elf, 0x3e) as usize;
+
+    let header = |index: usize| &elf[table + index * entry_size..table + (index + 1) * entry_size];
+    let names = u64_at(header(names_index), 24) as usize;
+    (0..count)
+        .map(|index| {
+            let header = header(index);
+            let name_start = names + u32_at(header, 0) as usize;
+            let name_len = elf[name_start..].iter().position(|&b| b == 0).unwrap();
+            Section {
+                name: String::from_utf8_lossy(&elf[name_start..name_start + name_len]).into_owned(),
+                addr: u64_at(header, 16),
+                offset: u64_at(header, 24),
+                size: u64_at(header, 32),
+                link: u32_at(header, 40),
+            }
+        })
+        .collect()
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Demangles a symbol in the legacy Rust mangling scheme, which the kernel is
+/// built with, dropping the trailing hash. Other names are returned as is.
+fn demangle(symbol: &str) -> String {
+    let Some(mut rest) = symbol.strip_prefix("_ZN").and_then(|rest| rest.strip_suffix('E')) else {
+        return symbol.to_owned();
+    };
+
+    let mut components = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Some(len) = rest[..digits].parse::<usize>().ok().filter(|&len| digits + len <= rest.len()) else {
+            return symbol.to_owned();
+        };
+        components.push(&rest[digits..digits + len]);
+        rest = &rest[digits + len..];
+    }
+    let is_hash = |component: &str| {
+        component.len() == 17
+            && component.starts_with('h')
+            && component[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    if components.last().is_some_and(|component| is_hash(component)) {
+        components.pop();
+    }
+
+    let mut demangled = String::new();
+    for (index, component) in components.iter().enumerate() {
+        if index > 0 {
+            demangled.push_str("::");
+        }
+        // a leading `$` escape is prefixed with an underscore
+        let mut component = match component.strip_prefix('_') {
+            Some(rest) if rest.starts_with('$') => rest,
+            _ => *component,
+        };
+        while !component.is_empty() {
+            if let Some(rest) = component.strip_prefix("..") {
+                demangled.push_str("::");
+                component = rest;
+            } else if let Some((escape, rest)) = component
+                .strip_prefix('$')
+                .and_then(|rest| rest.split_once('$'))
+            {
+                match unescape(escape) {
+                    Some(c) => demangled.push(c),
+                    None => {
+                        demangled.push('$');
+                        demangled.push_str(escape);
+                        demangled.push('$');
+                    }
+                }
+                component = rest;
+            } else {
+                let c = component.chars().next().unwrap();
+                demangled.push(c);
+                component = &component[c.len_utf8()..];
+            }
+        }
+    }
+    demangled
+}
+
+fn unescape(escape: &str) -> Option<char> {
+    Some(match escape {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => char::from_u32(u32::from_str_radix(escape.strip_prefix('u')?, 16).ok()?)?,
+    })
+}
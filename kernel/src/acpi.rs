@@ -1,8 +1,10 @@
 use core::ptr::NonNull;
+use alloc::vec::Vec;
 use bootloader_api::BootInfo;
 use acpi::InterruptModel;
 use acpi::platform::interrupt::Apic;
-use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
+use acpi::sdt::{SdtHeader, Signature};
+use acpi::{AcpiHandler, AcpiTable, AcpiTables, PhysicalMapping};
 
 #[derive(Clone)]
 struct AcpiMemHandler;
@@ -22,11 +24,137 @@ impl AcpiHandler for AcpiMemHandler {
     fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
 }
 
+/// System Resource Affinity Table: which NUMA node (proximity domain) each
+/// processor and memory range belongs to.
+#[repr(C, packed)]
+struct Srat {
+    header: SdtHeader,
+    _reserved: [u8; 12],
+}
+
+unsafe impl AcpiTable for Srat {
+    const SIGNATURE: Signature = Signature::SRAT;
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+/// System Locality Information Table: the relative distances between NUMA
+/// nodes, as a matrix of `locality_count` * `locality_count` bytes.
+#[repr(C, packed)]
+struct Slit {
+    header: SdtHeader,
+    locality_count: u64,
+}
+
+unsafe impl AcpiTable for Slit {
+    const SIGNATURE: Signature = Signature::SLIT;
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+const SRAT_PROCESSOR_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_X2APIC_AFFINITY: u8 = 2;
+const SRAT_ENABLED: u32 = 1;
+
+/// A memory range and the NUMA node it belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAffinity {
+    pub base: u64,
+    pub length: u64,
+    pub node: u32,
+}
+
+/// A processor, by APIC ID, and the NUMA node it belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorAffinity {
+    pub apic_id: u32,
+    pub node: u32,
+}
+
+/// The NUMA layout described by the SRAT and SLIT.
+#[derive(Debug, Clone, Default)]
+pub struct NumaInfo {
+    pub memory: Vec<MemoryAffinity>,
+    pub processors: Vec<ProcessorAffinity>,
+    /// Number of rows and columns of `distances`.
+    pub locality_count: usize,
+    /// Distance matrix from the SLIT, empty if there is none.
+    pub distances: Vec<u8>,
+}
+
+/// Returns the bytes of an ACPI table, including its header.
+unsafe fn table_bytes<T: AcpiTable>(table: &PhysicalMapping<AcpiMemHandler, T>) -> &[u8] {
+    let length = table.header().length as usize;
+    core::slice::from_raw_parts(table.virtual_start().as_ptr() as *const u8, length)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Parses the SRAT and, if present, the SLIT. Returns `None` if the machine
+/// does not describe a NUMA layout.
+fn parse_numa(acpi_tables: &AcpiTables<AcpiMemHandler>) -> Option<NumaInfo> {
+    let srat = acpi_tables.find_table::<Srat>().ok()?;
+    let bytes = unsafe { table_bytes(&srat) };
+    let mut info = NumaInfo::default();
+
+    let mut offset = core::mem::size_of::<Srat>();
+    while offset + 2 <= bytes.len() {
+        let (kind, length) = (bytes[offset], bytes[offset + 1] as usize);
+        if length < 2 || offset + length > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + length];
+        match kind {
+            SRAT_PROCESSOR_AFFINITY if length >= 16 && read_u32(entry, 4) & SRAT_ENABLED != 0 => {
+                let node = u32::from_le_bytes([entry[2], entry[9], entry[10], entry[11]]);
+                info.processors.push(ProcessorAffinity { apic_id: entry[3] as u32, node });
+            }
+            SRAT_MEMORY_AFFINITY if length >= 40 && read_u32(entry, 28) & SRAT_ENABLED != 0 => {
+                let base = read_u32(entry, 8) as u64 | (read_u32(entry, 12) as u64) << 32;
+                let length = read_u32(entry, 16) as u64 | (read_u32(entry, 20) as u64) << 32;
+                info.memory.push(MemoryAffinity { base, length, node: read_u32(entry, 2) });
+            }
+            SRAT_X2APIC_AFFINITY if length >= 24 && read_u32(entry, 12) & SRAT_ENABLED != 0 => {
+                info.processors.push(ProcessorAffinity {
+                    apic_id: read_u32(entry, 8),
+                    node: read_u32(entry, 4),
+                });
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+
+    if let Ok(slit) = acpi_tables.find_table::<Slit>() {
+        let bytes = unsafe { table_bytes(&slit) };
+        let count = slit.locality_count as usize;
+        let matrix = &bytes[core::mem::size_of::<Slit>()..];
+        if count.checked_mul(count).map_or(false, |size| size <= matrix.len()) {
+            info.locality_count = count;
+            info.distances = matrix[..count * count].to_vec();
+        }
+    }
+
+    Some(info)
+}
+
 pub fn init(boot_info: &'static BootInfo) -> Apic {
     let rsdp_addr = boot_info.rsdp_addr.into_option().unwrap();
     let acpi_tables = unsafe { AcpiTables::from_rsdp(AcpiMemHandler, rsdp_addr as usize) }.unwrap();
 
     log::info!("Find ACPI tables successfully!");
+    match parse_numa(&acpi_tables) {
+        Some(numa_info) => crate::memory::numa::init(numa_info),
+        None => log::info!("no SRAT, assuming a single NUMA node"),
+    }
+
     let platform_info = acpi_tables.platform_info().expect("Failed to get platform info!");
 
     let apic_info = match platform_info.interrupt_model {
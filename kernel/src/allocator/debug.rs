@@ -0,0 +1,169 @@
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use super::KernelAllocator;
+use crate::cpu::backtrace::ReturnAddresses;
+use crate::sync::NoPreemptMutex;
+
+/// Bytes of red zone after every allocation. The red zone in front of an
+/// allocation is at least this large, rounded up to the alignment.
+const RED_ZONE_SIZE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// Fill pattern of freshly allocated memory.
+const UNINIT_BYTE: u8 = 0xcd;
+/// Fill pattern of freed memory.
+const POISON_BYTE: u8 = 0xdd;
+
+/// Maximum number of live allocations that are tracked. Allocations beyond
+/// that still get red zones and poisoning, but are not listed.
+const MAX_TRACKED: usize = 1024;
+/// Number of return addresses recorded per allocation.
+pub const CALLER_DEPTH: usize = 6;
+
+/// A live allocation and the return addresses of the code that made it.
+#[derive(Debug, Clone, Copy)]
+pub struct LiveAllocation {
+    pub ptr: usize,
+    pub size: usize,
+    pub callers: [u64; CALLER_DEPTH],
+}
+
+struct LiveTable {
+    entries: [LiveAllocation; MAX_TRACKED],
+    len: usize,
+    /// Allocations that did not fit into `entries`.
+    untracked: usize,
+}
+
+/// Wraps the kernel allocator with red zones that are checked on free,
+/// poisoning of fresh and freed memory, and a table of live allocations.
+///
+/// Enabled with the `debug-alloc` feature. The table is a fixed array,
+/// since it cannot use the heap it is tracking.
+pub struct DebugAllocator {
+    inner: &'static KernelAllocator,
+    live: NoPreemptMutex<LiveTable>,
+}
+
+impl DebugAllocator {
+    pub const fn new(inner: &'static KernelAllocator) -> Self {
+        const EMPTY: LiveAllocation = LiveAllocation {
+            ptr: 0,
+            size: 0,
+            callers: [0; CALLER_DEPTH],
+        };
+        DebugAllocator {
+            inner,
+            live: NoPreemptMutex::new(LiveTable {
+                entries: [EMPTY; MAX_TRACKED],
+                len: 0,
+                untracked: 0,
+            }),
+        }
+    }
+
+    /// Returns the live allocations and the number of allocations that were
+    /// too many to track.
+    pub fn live_allocations(&self) -> (Vec<LiveAllocation>, usize) {
+        // allocate outside of the lock, since allocating takes it as well
+        let capacity = self.live.lock().len + 16;
+        let mut allocations = Vec::with_capacity(capacity);
+        let live = self.live.lock();
+        allocations.extend(live.entries[..live.len].iter().take(capacity).copied());
+        (allocations, live.untracked)
+    }
+}
+
+/// Returns the size of the red zone in front of an allocation with `layout`.
+fn front_size(layout: &Layout) -> usize {
+    RED_ZONE_SIZE.max(layout.align())
+}
+
+fn padded_layout(layout: &Layout) -> Option<Layout> {
+    let size = front_size(layout)
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let padded = match padded_layout(&layout) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let base = self.inner.alloc(padded);
+        if base.is_null() {
+            return base;
+        }
+
+        let front = front_size(&layout);
+        let user = base.add(front);
+        ptr::write_bytes(base, RED_ZONE_BYTE, front);
+        ptr::write_bytes(user, UNINIT_BYTE, layout.size());
+        ptr::write_bytes(user.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+
+        let mut callers = [0; CALLER_DEPTH];
+        for (slot, address) in callers.iter_mut().zip(ReturnAddresses::current()) {
+            *slot = address;
+        }
+        let mut live = self.live.lock();
+        if live.len < MAX_TRACKED {
+            let index = live.len;
+            live.entries[index] = LiveAllocation {
+                ptr: user as usize,
+                size: layout.size(),
+                callers,
+            };
+            live.len += 1;
+        } else {
+            live.untracked += 1;
+        }
+        user
+    }
+
+    unsafe fn dealloc(&self, user: *mut u8, layout: Layout) {
+        let front = front_size(&layout);
+        let base = user.sub(front);
+        let padded = padded_layout(&layout).unwrap();
+
+        let front_zone = core::slice::from_raw_parts(base, front);
+        let back_zone = core::slice::from_raw_parts(user.add(layout.size()), RED_ZONE_SIZE);
+        let overflow = back_zone.iter().position(|&byte| byte != RED_ZONE_BYTE);
+        let underflow = front_zone.iter().rposition(|&byte| byte != RED_ZONE_BYTE);
+
+        {
+            let mut live = self.live.lock();
+            let len = live.len;
+            match live.entries[..len].iter().position(|entry| entry.ptr == user as usize) {
+                Some(index) => {
+                    live.entries[index] = live.entries[len - 1];
+                    live.len -= 1;
+                }
+                None if live.untracked > 0 => live.untracked -= 1,
+                None => {}
+            }
+        }
+
+        if let Some(offset) = overflow {
+            panic!(
+                "heap overflow: {} bytes after the {} byte allocation at {:p} were overwritten",
+                offset + 1,
+                layout.size(),
+                user
+            );
+        }
+        if let Some(offset) = underflow {
+            panic!(
+                "heap underflow: {} bytes before the {} byte allocation at {:p} were overwritten",
+                front - offset,
+                layout.size(),
+                user
+            );
+        }
+
+        ptr::write_bytes(base, POISON_BYTE, padded.size());
+        self.inner.dealloc(base, padded);
+    }
+}
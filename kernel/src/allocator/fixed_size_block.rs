@@ -0,0 +1,217 @@
+use core::alloc::Layout;
+use core::mem;
+use core::ptr::NonNull;
+
+use linked_list_allocator::Heap;
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Size of the chunk that is taken from the fallback allocator and carved
+/// into blocks whenever a size class runs empty.
+pub const SLAB_SIZE: usize = 4096;
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Allocation counters for a single size class.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    /// Block size of the class, `0` for the large-block fallback.
+    pub block_size: usize,
+    pub allocs: u64,
+    pub frees: u64,
+    pub bytes_in_use: usize,
+    pub peak_bytes: usize,
+}
+
+impl SizeClassStats {
+    const fn new(block_size: usize) -> Self {
+        SizeClassStats {
+            block_size,
+            allocs: 0,
+            frees: 0,
+            bytes_in_use: 0,
+            peak_bytes: 0,
+        }
+    }
+
+    fn record_alloc(&mut self, bytes: usize) {
+        self.allocs += 1;
+        self.bytes_in_use += bytes;
+        self.peak_bytes = self.peak_bytes.max(self.bytes_in_use);
+    }
+
+    fn record_free(&mut self, bytes: usize) {
+        self.frees += 1;
+        self.bytes_in_use -= bytes;
+    }
+}
+
+/// Allocation counters for every size class plus the large-block fallback.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocStats {
+    pub classes: [SizeClassStats; BLOCK_SIZES.len()],
+    pub large: SizeClassStats,
+}
+
+/// An allocator that serves small allocations from per-size-class free lists
+/// and everything else from a linked list heap.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: Heap,
+    stats: AllocStats,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        const fn class_stats() -> [SizeClassStats; BLOCK_SIZES.len()] {
+            let mut classes = [SizeClassStats::new(0); BLOCK_SIZES.len()];
+            let mut i = 0;
+            while i < BLOCK_SIZES.len() {
+                classes[i].block_size = BLOCK_SIZES[i];
+                i += 1;
+            }
+            classes
+        }
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: Heap::empty(),
+            stats: AllocStats {
+                classes: class_stats(),
+                large: SizeClassStats::new(0),
+            },
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// The linked list heap that backs the size classes and large allocations.
+    pub fn fallback(&mut self) -> &mut Heap {
+        &mut self.fallback_allocator
+    }
+
+    pub fn stats(&self) -> AllocStats {
+        self.stats
+    }
+
+    /// Returns the size of the largest block the fallback heap can hand out
+    /// right now.
+    ///
+    /// The linked list heap does not expose its free list, so this probes it
+    /// with a binary search of allocations that are released immediately.
+    pub fn largest_free_block(&mut self) -> usize {
+        let align = mem::align_of::<usize>();
+        let (mut low, mut high) = (0, self.fallback_allocator.free());
+        while low < high {
+            let size = (low + high + 1) / 2;
+            let layout = match Layout::from_size_align(size, align) {
+                Ok(layout) => layout,
+                Err(_) => break,
+            };
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                    low = size;
+                }
+                Err(()) => high = size - 1,
+            }
+        }
+        low
+    }
+
+    /// Allocates a block for `layout`, or returns `None` if the fallback heap
+    /// is exhausted.
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        match list_index(&layout) {
+            Some(index) => {
+                if self.list_heads[index].is_none() {
+                    self.refill(index)?;
+                }
+                let node = self.list_heads[index].take()?;
+                self.list_heads[index] = node.next.take();
+                self.stats.classes[index].record_alloc(BLOCK_SIZES[index]);
+                Some(NonNull::from(node).cast())
+            }
+            None => {
+                let ptr = self.fallback_allocator.allocate_first_fit(layout).ok()?;
+                self.stats.large.record_alloc(layout.size());
+                Some(ptr)
+            }
+        }
+    }
+
+    /// Returns a block to its size class or to the fallback heap.
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was returned by `alloc` with the same `layout`.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr.as_ptr() as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+                self.stats.classes[index].record_free(BLOCK_SIZES[index]);
+            }
+            None => {
+                self.fallback_allocator.deallocate(ptr, layout);
+                self.stats.large.record_free(layout.size());
+            }
+        }
+    }
+
+    /// Carves a fresh slab from the fallback heap into blocks for the size
+    /// class `index`. Falls back to a single block when no full slab fits.
+    fn refill(&mut self, index: usize) -> Option<()> {
+        let block_size = BLOCK_SIZES[index];
+        let (slab, slab_size) = Layout::from_size_align(SLAB_SIZE, block_size)
+            .ok()
+            .and_then(|layout| self.fallback_allocator.allocate_first_fit(layout).ok())
+            .map(|slab| (slab, SLAB_SIZE))
+            .or_else(|| {
+                let layout = Layout::from_size_align(block_size, block_size).ok()?;
+                let block = self.fallback_allocator.allocate_first_fit(layout).ok()?;
+                Some((block, block_size))
+            })?;
+
+        for offset in (0..slab_size).step_by(block_size).rev() {
+            let node_ptr = unsafe { slab.as_ptr().add(offset) } as *mut ListNode;
+            unsafe {
+                node_ptr.write(ListNode {
+                    next: self.list_heads[index].take(),
+                });
+                self.list_heads[index] = Some(&mut *node_ptr);
+            }
+        }
+        Some(())
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl Send for FixedSizeBlockAllocator {}
@@ -0,0 +1,281 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use linked_list_allocator::Heap;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTableFlags, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory::vmm::{self, VmaKind};
+use crate::memory::{BitmapFrameAllocator, MAPPER, FRAME_ALLOCATOR};
+use crate::sync::NoPreemptMutex;
+
+pub mod fixed_size_block;
+use fixed_size_block::{FixedSizeBlockAllocator, SLAB_SIZE};
+pub use fixed_size_block::AllocStats;
+#[cfg(feature = "debug-alloc")]
+pub mod debug;
+
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Size of the virtual range reserved for the heap, and the default limit.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// Flags the heap pages are mapped with.
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+/// The heap grows by at least this many bytes at a time.
+const HEAP_GROW_SIZE: usize = 64 * 1024; // 64 KiB
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+#[cfg_attr(not(feature = "debug-alloc"), global_allocator)]
+static ALLOCATOR: KernelAllocator = KernelAllocator::empty();
+
+#[cfg(feature = "debug-alloc")]
+#[global_allocator]
+static DEBUG_ALLOCATOR: debug::DebugAllocator = debug::DebugAllocator::new(&ALLOCATOR);
+
+/// A snapshot of the kernel heap's size and usage.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapUsage {
+    /// Bytes currently mapped for the heap.
+    pub size: usize,
+    /// Bytes handed out to allocations.
+    pub used: usize,
+    /// Maximum size the heap may grow to.
+    pub limit: usize,
+    /// Number of times the heap was grown.
+    pub grow_count: usize,
+}
+
+/// The global allocator: size-class free lists on top of a linked list heap
+/// that maps additional pages on demand, up to the configured heap limit.
+pub struct KernelAllocator {
+    inner: NoPreemptMutex<FixedSizeBlockAllocator>,
+    grow_count: AtomicUsize,
+}
+
+impl KernelAllocator {
+    pub const fn empty() -> Self {
+        KernelAllocator {
+            inner: NoPreemptMutex::new(FixedSizeBlockAllocator::new()),
+            grow_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Maps at least `min_bytes` more memory at the top of the heap and hands
+    /// it to the allocator. Returns the number of bytes the heap grew by.
+    fn grow(&self, heap: &mut Heap, min_bytes: usize) -> Result<usize, MapToError<Size4KiB>> {
+        let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+        let wanted = align_up(min_bytes.max(HEAP_GROW_SIZE), Size4KiB::SIZE as usize);
+        // past its first 2 MiB, the heap grows to 2 MiB boundaries, so that
+        // `map_heap_pages` can use huge pages from then on
+        let huge = Size2MiB::SIZE as usize;
+        let wanted = if heap.size() >= huge {
+            align_up(heap.top() + wanted, huge) - heap.top()
+        } else {
+            wanted
+        };
+        let by = wanted.min(limit.saturating_sub(heap.size()));
+        if by < min_bytes || by == 0 {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
+        map_heap_pages(heap.top(), by)?;
+        unsafe { heap.extend(by) };
+        self.grow_count.fetch_add(1, Ordering::Relaxed);
+        Ok(by)
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut grown = 0;
+        let result = {
+            let mut allocator = self.inner.lock();
+            loop {
+                if let Some(ptr) = allocator.alloc(layout) {
+                    break ptr.as_ptr();
+                }
+                // a size class refill needs at most one slab
+                let needed = layout.size().max(SLAB_SIZE) + layout.align();
+                match self.grow(allocator.fallback(), needed) {
+                    Ok(by) => grown += by,
+                    Err(_) => break ptr::null_mut(),
+                }
+            }
+        };
+
+        // the logger allocates, so only log once the heap lock is released
+        if grown > 0 {
+            log::debug!("heap grew by {} KiB to {} KiB", grown / 1024, heap_usage().size / 1024);
+        }
+        result
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.lock().dealloc(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+/// Returns the current size and usage of the kernel heap.
+pub fn heap_usage() -> HeapUsage {
+    let mut allocator = ALLOCATOR.inner.lock();
+    let heap = allocator.fallback();
+    HeapUsage {
+        size: heap.size(),
+        used: heap.used(),
+        limit: HEAP_LIMIT.load(Ordering::Relaxed),
+        grow_count: ALLOCATOR.grow_count.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the live heap allocations with the return addresses of their
+/// callers, and the number of allocations that were too many to track.
+#[cfg(feature = "debug-alloc")]
+pub fn live_allocations() -> (alloc::vec::Vec<debug::LiveAllocation>, usize) {
+    DEBUG_ALLOCATOR.live_allocations()
+}
+
+/// Returns the per-size-class allocation counters.
+pub fn stats() -> AllocStats {
+    ALLOCATOR.inner.lock().stats()
+}
+
+/// Sets the maximum size the heap may grow to, at most [`HEAP_MAX_SIZE`].
+/// Memory that is already mapped is never given back, so lowering the limit
+/// below the current size only stops further growth.
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(bytes.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+/// Maps `size` bytes of fresh frames starting at the heap address `start`,
+/// using a 2 MiB page for every aligned 2 MiB of the range that a free
+/// 2 MiB frame can be found for.
+fn map_heap_pages(start: usize, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        match map_heap_page(&mut mapper, &mut frame_allocator, VirtAddr::new(addr as u64), end - addr) {
+            Ok(mapped) => addr += mapped,
+            Err(err) => {
+                // roll back the pages mapped so far so a later attempt starts clean
+                unmap_heap_pages(&mut mapper, &mut frame_allocator, start, addr);
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Maps a single page at `addr` and returns its size.
+fn map_heap_page(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+    left: usize,
+) -> Result<usize, MapToError<Size4KiB>> {
+    if addr.is_aligned(Size2MiB::SIZE) && left >= Size2MiB::SIZE as usize {
+        if let Some(frame) = FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator) {
+            let page = Page::<Size2MiB>::containing_address(addr);
+            return match unsafe { mapper.map_to(page, frame, HEAP_FLAGS, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(Size2MiB::SIZE as usize)
+                }
+                Err(_) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(MapToError::FrameAllocationFailed)
+                }
+            };
+        }
+    }
+
+    let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, HEAP_FLAGS, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(Size4KiB::SIZE as usize)
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+/// Unmaps and frees the heap pages of `start..end`, whatever their size.
+fn unmap_heap_pages(mapper: &mut OffsetPageTable, frame_allocator: &mut BitmapFrameAllocator, start: usize, end: usize) {
+    let mut addr = start;
+    while addr < end {
+        let virt = VirtAddr::new(addr as u64);
+        if let Ok((frame, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(virt)) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        } else if let Ok((frame, flush)) = mapper.unmap(Page::<Size2MiB>::containing_address(virt)) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            addr += (Size2MiB::SIZE - Size4KiB::SIZE) as usize;
+        }
+        addr += Size4KiB::SIZE as usize;
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+pub fn init_heap(
+) {
+    let heap_start = vmm::allocate_aligned(HEAP_MAX_SIZE as u64, Size2MiB::SIZE, "kernel heap", VmaKind::Heap, HEAP_FLAGS)
+        .expect("failed to reserve the heap's virtual range")
+        .as_u64() as usize;
+    map_heap_pages(heap_start, HEAP_SIZE).expect("failed to map heap pages");
+
+    unsafe {
+        ALLOCATOR.inner.lock().init(heap_start, HEAP_SIZE);
+    }
+}
+
+/// Called when an allocation fails even after trying to grow the heap.
+///
+/// Nothing in here may allocate, and the framebuffer or serial lock may be
+/// held by the code that ran out of memory, so only the emergency printing
+/// path is used.
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::emergency_println!(
+        "ALLOCATION FAILED: size {} align {}",
+        layout.size(),
+        layout.align()
+    );
+
+    match ALLOCATOR.inner.try_lock() {
+        Some(mut allocator) => {
+            let largest_free_block = allocator.largest_free_block();
+            let heap = allocator.fallback();
+            crate::emergency_println!(
+                "heap: {} KiB mapped, {} KiB used, {} KiB limit, largest free block {} bytes",
+                heap.size() / 1024,
+                heap.used() / 1024,
+                HEAP_LIMIT.load(Ordering::Relaxed) / 1024,
+                largest_free_block
+            );
+        }
+        None => crate::emergency_println!("heap: allocator is locked"),
+    }
+
+    crate::cpu::backtrace::print(None, crate::cpu::backtrace::ReturnAddresses::current());
+
+    crate::apply_panic_policy();
+}
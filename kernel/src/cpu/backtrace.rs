@@ -0,0 +1,113 @@
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+use crate::memory::{self, PHYS_MEM_OFFSET};
+
+/// Maximum number of frames that are walked before giving up.
+const MAX_FRAMES: usize = 32;
+/// Frames further than this above the current stack pointer are treated as
+/// garbage, which keeps the walk from wandering off the stack.
+const MAX_STACK_SPAN: u64 = 1024 * 1024;
+
+/// Iterates over the return addresses of the current call stack by following
+/// the saved frame pointers.
+///
+/// This relies on the kernel being built with `-C force-frame-pointers=yes`.
+pub struct ReturnAddresses {
+    rbp: u64,
+    stack_low: u64,
+    remaining: usize,
+}
+
+impl ReturnAddresses {
+    #[inline(always)]
+    pub fn current() -> Self {
+        let rbp: u64;
+        let rsp: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        }
+        ReturnAddresses {
+            rbp,
+            stack_low: rsp,
+            remaining: MAX_FRAMES,
+        }
+    }
+
+    /// Walks the frames starting at the frame pointer `rbp`, e.g. the one
+    /// saved when an exception interrupted the kernel.
+    pub fn starting_at(rbp: u64) -> Self {
+        ReturnAddresses {
+            rbp,
+            stack_low: rbp,
+            remaining: MAX_FRAMES,
+        }
+    }
+}
+
+impl Iterator for ReturnAddresses {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+        if self.remaining == 0
+            || rbp % 8 != 0
+            || rbp < self.stack_low
+            || rbp - self.stack_low > MAX_STACK_SPAN
+        {
+            return None;
+        }
+        self.remaining -= 1;
+        // a corrupt frame pointer must not fault in the middle of a report
+        if !is_mapped(rbp) || !is_mapped(rbp + 8) {
+            return None;
+        }
+
+        // the frame layout is [saved rbp, return address]
+        let frame = rbp as *const u64;
+        let (saved_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            return None;
+        }
+        // frames must grow towards higher addresses, otherwise the chain is broken
+        self.stack_low = rbp + 16;
+        self.rbp = if saved_rbp > rbp { saved_rbp } else { 0 };
+        Some(return_address)
+    }
+}
+
+/// Returns whether `addr` can be read without faulting. Walks the page tables
+/// directly, since the walk may run while the mapper lock is held.
+fn is_mapped(addr: u64) -> bool {
+    let (Ok(addr), Ok(phys_mem_offset)) = (VirtAddr::try_new(addr), PHYS_MEM_OFFSET.try_get()) else {
+        return false;
+    };
+    unsafe { memory::translate_addr(addr, *phys_mem_offset) }.is_some()
+}
+
+/// Prints one line per frame, `#n 0xaddr function+offset`, through the
+/// emergency printing path. `rip` is the address of the faulting
+/// instruction, if there is one, and comes before the return addresses.
+pub fn print(rip: Option<u64>, return_addresses: ReturnAddresses) {
+    crate::emergency_println!("backtrace:");
+    // a return address points behind the call, which may already be the
+    // next function, so the symbol is looked up for the byte before it
+    let frames = rip
+        .map(|rip| (rip, rip))
+        .into_iter()
+        .chain(return_addresses.map(|address| (address, address - 1)));
+    for (index, (address, lookup_address)) in frames.enumerate() {
+        match crate::symbols::lookup(lookup_address) {
+            Some(symbol) => crate::emergency_println!(
+                "  #{} {:#x} {}+{:#x}",
+                index,
+                address,
+                symbol.name,
+                address - symbol.address
+            ),
+            None => crate::emergency_println!("  #{} {:#x} <unknown>", index, address),
+        }
+    }
+}
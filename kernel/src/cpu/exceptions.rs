@@ -0,0 +1,432 @@
+use core::arch::global_asm;
+use core::fmt;
+
+use x86_64::instructions::tables::sidt;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use crate::cpu::backtrace::{self, ReturnAddresses};
+use crate::cpu::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, PAGE_FAULT_IST_INDEX};
+use crate::cpu::user::{self, UserExit};
+use crate::emergency_println;
+use crate::memory::page_fault::{self, FaultError};
+
+pub const DIVIDE_ERROR: u64 = 0;
+pub const DEBUG: u64 = 1;
+pub const NON_MASKABLE_INTERRUPT: u64 = 2;
+pub const BREAKPOINT: u64 = 3;
+pub const OVERFLOW: u64 = 4;
+pub const BOUND_RANGE_EXCEEDED: u64 = 5;
+pub const INVALID_OPCODE: u64 = 6;
+pub const DEVICE_NOT_AVAILABLE: u64 = 7;
+pub const DOUBLE_FAULT: u64 = 8;
+pub const INVALID_TSS: u64 = 10;
+pub const SEGMENT_NOT_PRESENT: u64 = 11;
+pub const STACK_SEGMENT_FAULT: u64 = 12;
+pub const GENERAL_PROTECTION_FAULT: u64 = 13;
+pub const PAGE_FAULT: u64 = 14;
+pub const X87_FLOATING_POINT: u64 = 16;
+pub const ALIGNMENT_CHECK: u64 = 17;
+pub const MACHINE_CHECK: u64 = 18;
+pub const SIMD_FLOATING_POINT: u64 = 19;
+pub const VIRTUALIZATION: u64 = 20;
+pub const CONTROL_PROTECTION: u64 = 21;
+pub const HYPERVISOR_INJECTION: u64 = 28;
+pub const VMM_COMMUNICATION: u64 = 29;
+pub const SECURITY_EXCEPTION: u64 = 30;
+
+/// The state of the interrupted code, as saved by the exception entry stubs.
+///
+/// The general purpose registers are pushed by the stub, `vector` and
+/// `error_code` (`0` for exceptions without one) by the stub or the CPU, and
+/// the rest by the CPU.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    /// Returns whether the exception was raised in ring 3.
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rax {:016x} rbx {:016x} rcx {:016x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "rdx {:016x} rsi {:016x} rdi {:016x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "rbp {:016x} rsp {:016x} r8  {:016x}", self.rbp, self.rsp, self.r8)?;
+        writeln!(f, "r9  {:016x} r10 {:016x} r11 {:016x}", self.r9, self.r10, self.r11)?;
+        writeln!(f, "r12 {:016x} r13 {:016x} r14 {:016x}", self.r12, self.r13, self.r14)?;
+        writeln!(f, "r15 {:016x} rip {:016x} rflags {:08x}", self.r15, self.rip, self.rflags)?;
+        writeln!(f, "cs  {:04x} ss  {:04x}", self.cs, self.ss)?;
+        write!(
+            f,
+            "cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )
+    }
+}
+
+/// Returns the mnemonic and name of an exception vector.
+pub fn exception_name(vector: u64) -> (&'static str, &'static str) {
+    match vector {
+        DIVIDE_ERROR => ("#DE", "divide error"),
+        DEBUG => ("#DB", "debug"),
+        NON_MASKABLE_INTERRUPT => ("NMI", "non-maskable interrupt"),
+        BREAKPOINT => ("#BP", "breakpoint"),
+        OVERFLOW => ("#OF", "overflow"),
+        BOUND_RANGE_EXCEEDED => ("#BR", "bound range exceeded"),
+        INVALID_OPCODE => ("#UD", "invalid opcode"),
+        DEVICE_NOT_AVAILABLE => ("#NM", "device not available"),
+        DOUBLE_FAULT => ("#DF", "double fault"),
+        INVALID_TSS => ("#TS", "invalid TSS"),
+        SEGMENT_NOT_PRESENT => ("#NP", "segment not present"),
+        STACK_SEGMENT_FAULT => ("#SS", "stack segment fault"),
+        GENERAL_PROTECTION_FAULT => ("#GP", "general protection fault"),
+        PAGE_FAULT => ("#PF", "page fault"),
+        X87_FLOATING_POINT => ("#MF", "x87 floating point exception"),
+        ALIGNMENT_CHECK => ("#AC", "alignment check"),
+        MACHINE_CHECK => ("#MC", "machine check"),
+        SIMD_FLOATING_POINT => ("#XM", "SIMD floating point exception"),
+        VIRTUALIZATION => ("#VE", "virtualization exception"),
+        CONTROL_PROTECTION => ("#CP", "control protection exception"),
+        HYPERVISOR_INJECTION => ("#HV", "hypervisor injection exception"),
+        VMM_COMMUNICATION => ("#VC", "VMM communication exception"),
+        SECURITY_EXCEPTION => ("#SX", "security exception"),
+        _ => ("#??", "unknown exception"),
+    }
+}
+
+/// Decodes the error code of the exceptions that report a segment selector.
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return write!(f, "no selector");
+        }
+        let table = match (code >> 1) & 0b11 {
+            0 => "GDT",
+            2 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} index {}", table, (code >> 3) & 0x1fff)?;
+        if code & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+        Ok(())
+    }
+}
+
+// Entry stubs for every exception. They push a dummy error code if the CPU
+// does not, push the vector and all general purpose registers, and pass the
+// resulting `ExceptionFrame` to `exception_dispatch`. The frame is always 22
+// quadwords large, so the stack stays 16 byte aligned for the call.
+macro_rules! exception_stub {
+    ($name:literal, $vector:literal) => {
+        concat!(
+            ".global ", $name, "\n",
+            $name, ":\n",
+            "    push 0\n",
+            "    push ", $vector, "\n",
+            "    jmp exception_common\n",
+        )
+    };
+    ($name:literal, $vector:literal, error_code) => {
+        concat!(
+            ".global ", $name, "\n",
+            $name, ":\n",
+            "    push ", $vector, "\n",
+            "    jmp exception_common\n",
+        )
+    };
+}
+
+global_asm!(
+    exception_stub!("exception_stub_0", "0"),
+    exception_stub!("exception_stub_1", "1"),
+    exception_stub!("exception_stub_2", "2"),
+    exception_stub!("exception_stub_3", "3"),
+    exception_stub!("exception_stub_4", "4"),
+    exception_stub!("exception_stub_5", "5"),
+    exception_stub!("exception_stub_6", "6"),
+    exception_stub!("exception_stub_7", "7"),
+    exception_stub!("exception_stub_8", "8", error_code),
+    exception_stub!("exception_stub_10", "10", error_code),
+    exception_stub!("exception_stub_11", "11", error_code),
+    exception_stub!("exception_stub_12", "12", error_code),
+    exception_stub!("exception_stub_13", "13", error_code),
+    exception_stub!("exception_stub_14", "14", error_code),
+    exception_stub!("exception_stub_16", "16"),
+    exception_stub!("exception_stub_17", "17", error_code),
+    exception_stub!("exception_stub_18", "18"),
+    exception_stub!("exception_stub_19", "19"),
+    exception_stub!("exception_stub_20", "20"),
+    exception_stub!("exception_stub_21", "21", error_code),
+    exception_stub!("exception_stub_28", "28"),
+    exception_stub!("exception_stub_29", "29", error_code),
+    exception_stub!("exception_stub_30", "30", error_code),
+    "exception_common:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {dispatch}",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    // drop the vector and the error code
+    "    add rsp, 16",
+    "    iretq",
+    dispatch = sym exception_dispatch,
+);
+
+extern "C" {
+    fn exception_stub_0();
+    fn exception_stub_1();
+    fn exception_stub_2();
+    fn exception_stub_3();
+    fn exception_stub_4();
+    fn exception_stub_5();
+    fn exception_stub_6();
+    fn exception_stub_7();
+    fn exception_stub_8();
+    fn exception_stub_10();
+    fn exception_stub_11();
+    fn exception_stub_12();
+    fn exception_stub_13();
+    fn exception_stub_14();
+    fn exception_stub_16();
+    fn exception_stub_17();
+    fn exception_stub_18();
+    fn exception_stub_19();
+    fn exception_stub_20();
+    fn exception_stub_21();
+    fn exception_stub_28();
+    fn exception_stub_29();
+    fn exception_stub_30();
+}
+
+/// Points every architectural exception of `idt` at its entry stub.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let addr = |stub: unsafe extern "C" fn()| VirtAddr::new(stub as usize as u64);
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(exception_stub_0));
+        idt.debug.set_handler_addr(addr(exception_stub_1));
+        idt.non_maskable_interrupt.set_handler_addr(addr(exception_stub_2));
+        idt.breakpoint.set_handler_addr(addr(exception_stub_3));
+        idt.overflow.set_handler_addr(addr(exception_stub_4));
+        idt.bound_range_exceeded.set_handler_addr(addr(exception_stub_5));
+        idt.invalid_opcode.set_handler_addr(addr(exception_stub_6));
+        idt.device_not_available.set_handler_addr(addr(exception_stub_7));
+        idt.double_fault
+            .set_handler_addr(addr(exception_stub_8))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(addr(exception_stub_10));
+        idt.segment_not_present.set_handler_addr(addr(exception_stub_11));
+        idt.stack_segment_fault.set_handler_addr(addr(exception_stub_12));
+        idt.general_protection_fault.set_handler_addr(addr(exception_stub_13));
+        idt.page_fault
+            .set_handler_addr(addr(exception_stub_14))
+            .set_stack_index(PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point.set_handler_addr(addr(exception_stub_16));
+        idt.alignment_check.set_handler_addr(addr(exception_stub_17));
+        idt.machine_check
+            .set_handler_addr(addr(exception_stub_18))
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(addr(exception_stub_19));
+        idt.virtualization.set_handler_addr(addr(exception_stub_20));
+        reserved_entry(idt, CONTROL_PROTECTION).set_handler_addr(addr(exception_stub_21));
+        reserved_entry(idt, HYPERVISOR_INJECTION).set_handler_addr(addr(exception_stub_28));
+        idt.vmm_communication_exception.set_handler_addr(addr(exception_stub_29));
+        idt.security_exception.set_handler_addr(addr(exception_stub_30));
+    }
+}
+
+/// Returns the entry for one of the vectors 21 to 28, which the `x86_64`
+/// crate keeps private as reserved. The table is `repr(C)`, with one 16 byte
+/// entry per vector in vector order.
+fn reserved_entry(idt: &mut InterruptDescriptorTable, vector: u64) -> &mut Entry<HandlerFunc> {
+    assert!((21..=28).contains(&vector), "vector {} has a field of its own", vector);
+    unsafe { &mut *(idt as *mut InterruptDescriptorTable as *mut Entry<HandlerFunc>).add(vector as usize) }
+}
+
+/// Turns the interrupt stack of the page fault gate on or off, and returns
+/// whether it was on. While it is off, a page fault pushes its frame onto the
+/// stack it was raised on, like any other exception.
+///
+/// The gate is edited in place, at its hardware layout: the interrupt stack
+/// index is in the low three bits of the 16-bit options word at byte 4.
+fn set_page_fault_ist(enabled: bool) -> bool {
+    let gate = sidt().base.as_u64() + PAGE_FAULT * 16;
+    let options = (gate + 4) as *mut u16;
+    unsafe {
+        let previous = options.read_volatile();
+        let ist = if enabled { PAGE_FAULT_IST_INDEX + 1 } else { 0 };
+        options.write_volatile(previous & !0b111 | ist);
+        previous & 0b111 != 0
+    }
+}
+
+/// Called by the entry stubs. Returning resumes the interrupted code with
+/// the (possibly modified) register state in `frame`.
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        BREAKPOINT => {
+            // the logger allocates and locks, and the breakpoint may be anywhere
+            emergency_println!("breakpoint at {:#x}", frame.rip);
+            return;
+        }
+        PAGE_FAULT => {
+            let addr = Cr2::read();
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            // a fault in the handler itself must not start over at the top of
+            // the stack this frame is on
+            let ist_enabled = set_page_fault_ist(false);
+            let result = page_fault::handle_page_fault(addr, error_code);
+            set_page_fault_ist(ist_enabled);
+            match result {
+                Ok(()) => return,
+                Err(_) if frame.from_user_mode() && deliver_to_process(frame) => return,
+                Err(_) if apply_fixup(frame) => return,
+                Err(FaultError::StackOverflow(name)) => {
+                    fatal_exception(frame, format_args!("stack overflow in {} at {:?}", name, addr))
+                }
+                Err(err) => fatal_exception(
+                    frame,
+                    format_args!("{:?} accessing {:?}, {:?}", err, addr, error_code),
+                ),
+            }
+        }
+        MACHINE_CHECK => {
+            // hardware errors are never the fault of the interrupted process
+            crate::cpu::mca::handle_machine_check(frame);
+            return;
+        }
+        _ => {}
+    }
+
+    if frame.from_user_mode() && deliver_to_process(frame) {
+        return;
+    }
+    if frame.vector == GENERAL_PROTECTION_FAULT && apply_fixup(frame) {
+        return;
+    }
+
+    match frame.vector {
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            fatal_exception(frame, format_args!("{}", SelectorErrorCode(frame.error_code)))
+        }
+        _ => fatal_exception(frame, format_args!("error code {:#x}", frame.error_code)),
+    }
+}
+
+/// Resumes kernel code that faulted on a user access it expected to fail at
+/// its fixup. Returns `false` if the fault was not expected.
+fn apply_fixup(frame: &mut ExceptionFrame) -> bool {
+    if frame.from_user_mode() {
+        return false;
+    }
+    match crate::syscall::exception_fixup(frame.rip) {
+        Some(fixup) => {
+            frame.rip = fixup;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Hands an exception raised in user mode to the process that caused it.
+/// Returns `false` if there is no process to deliver it to.
+fn deliver_to_process(frame: &ExceptionFrame) -> bool {
+    if !user::is_running() {
+        return false;
+    }
+    // reported by `user::run` once interrupts are back on: a preempted
+    // thread may hold a console lock, and spinning on it here with
+    // interrupts off would never let that thread run again
+    user::leave(UserExit::Fault {
+        vector: frame.vector,
+        error_code: frame.error_code,
+        rip: frame.rip,
+        addr: (frame.vector == PAGE_FAULT).then(|| Cr2::read().as_u64()),
+    })
+}
+
+/// Reports an exception the kernel cannot recover from, with a register
+/// dump and a backtrace, and applies the panic policy.
+///
+/// Only the emergency printing path is used, since the exception may have
+/// interrupted code that holds the framebuffer or serial lock.
+pub fn fatal_exception(frame: &ExceptionFrame, detail: fmt::Arguments) -> ! {
+    x86_64::instructions::interrupts::disable();
+    // the report may fault, which must not overwrite the page fault stack
+    set_page_fault_ist(false);
+    let (mnemonic, name) = exception_name(frame.vector);
+    let mode = if frame.from_user_mode() { "user" } else { "kernel" };
+    emergency_println!("EXCEPTION: {} {} in {} mode: {}", mnemonic, name, mode, detail);
+    emergency_println!("{}", frame);
+
+    // user stacks are not walked, they may be unmapped or hostile
+    if frame.from_user_mode() {
+        emergency_println!("called from: {:#x}", frame.rip);
+    } else {
+        backtrace::print(Some(frame.rip), ReturnAddresses::starting_at(frame.rbp));
+    }
+
+    crate::apply_panic_policy();
+}
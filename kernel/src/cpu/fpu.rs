@@ -0,0 +1,63 @@
+use core::arch::asm;
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// Default x87 control word: every exception masked, extended precision.
+const DEFAULT_FCW: u16 = 0x037f;
+/// Default MXCSR: every SSE exception masked, round to nearest.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// The x87 and SSE registers in the layout `fxsave` stores them in.
+///
+/// The kernel itself is built without floating point or SIMD instructions,
+/// so only user code ever changes these registers, and they are saved and
+/// restored with the thread that runs it.
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// Returns the state after `fninit` and an MXCSR reset.
+    pub const fn new() -> Self {
+        let mut bytes = [0; 512];
+        let fcw = DEFAULT_FCW.to_le_bytes();
+        bytes[0] = fcw[0];
+        bytes[1] = fcw[1];
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        bytes[24] = mxcsr[0];
+        bytes[25] = mxcsr[1];
+        bytes[26] = mxcsr[2];
+        bytes[27] = mxcsr[3];
+        FpuState(bytes)
+    }
+
+    /// Stores the registers of this processor in `self`.
+    pub fn save(&mut self) {
+        unsafe { asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags)) };
+    }
+
+    /// Loads the registers of this processor from `self`.
+    pub fn restore(&self) {
+        unsafe { asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags, readonly)) };
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Enables the x87 and SSE instructions for user code, with exceptions
+/// reported through #MF and #XM, and `fxsave` and `fxrstor` for switching
+/// their state.
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+    FpuState::new().restore();
+}
@@ -1,41 +1,69 @@
+use core::cell::UnsafeCell;
+
 use spin::Lazy;
 use x86_64::registers::segmentation::{DS, ES, SS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::KernelStack;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Size of each interrupt stack.
+const IST_STACK_SIZE: u64 = 4096 * 5;
+
+/// The TSS, which the CPU keeps reading after it is loaded. Only
+/// `privilege_stack_table[0]` changes afterwards, see [`set_kernel_stack`].
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
 
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        let stack_end = stack_start + STACK_SIZE;
-        stack_end
+static TSS: Lazy<Tss> = Lazy::new(|| {
+    let mut tss = TaskStateSegment::new();
+    let ist_stack = |name| {
+        KernelStack::new(IST_STACK_SIZE, name)
+            .expect("failed to allocate an interrupt stack")
+            .leak()
     };
-    tss
+    // page faults get their own stack so that a kernel stack overflow can be
+    // reported instead of turning into a double fault. The page fault handler
+    // turns it off while it runs, so that faults inside the handler nest on
+    // this stack instead of starting over at its top
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack("double fault stack");
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = ist_stack("page fault stack");
+    // a machine check can interrupt any code, including the other handlers
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack("machine check stack");
+    Tss(UnsafeCell::new(tss))
 });
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// The segment selectors of the GDT. The user data segment comes right
+/// before the user code segment, which is the order `sysret` expects.
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
 static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
     let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
 
-    let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
     (
         gdt,
         Selectors {
             code_selector,
             data_selector,
+            user_data_selector,
+            user_code_selector,
             tss_selector,
         },
     )
@@ -54,3 +82,14 @@ pub fn init_gdt() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Sets the stack the CPU switches to when an interrupt or exception arrives
+/// in user mode. Must be updated whenever a different thread is about to run
+/// in user mode.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] = stack_top };
+}
@@ -5,7 +5,7 @@ use spin::Once;
 use x86_64::instructions::port::PortReadOnly;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
-use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::PrivilegeLevel;
 
 use crate::println;
 use crate::x2apic::LAPIC;
@@ -43,20 +43,18 @@ impl InterruptIndex {
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.general_protection_fault.set_handler_fn(general_fault_handler);
-    unsafe {
-        idt.double_fault
-            .set_handler_fn(double_fault_handler)
-            .set_stack_index(crate::cpu::gdt::DOUBLE_FAULT_IST_INDEX); // new
-    }
-    idt.page_fault.set_handler_fn(page_fault_handler);
+    crate::cpu::exceptions::install(&mut idt);
     idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler); // new
     idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
     idt[InterruptIndex::ApicError.as_usize()].set_handler_fn(lapic_error);
     idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(spurious_interrupt);
     idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt);
-    idt[InterruptIndex::Syscall.as_usize()].set_handler_fn(syscall_handler);
+    // the fallback for `syscall`, which user mode may raise with `int`
+    unsafe {
+        idt[InterruptIndex::Syscall.as_usize()]
+            .set_handler_addr(crate::syscall::interrupt_entry())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
     idt
 });
 
@@ -76,7 +74,8 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    crate::task::timer::tick();
     if let Ok(func) = crate::TIMER_FN.try_get() {
         func();
     }
@@ -85,14 +84,17 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
         LAPIC.try_get().unwrap().lock()
             .end_of_interrupt()
     }
-}
+    // may switch to another thread, which is why the interrupt has to be
+    // acknowledged first
+    crate::thread::preempt();
 
-extern "x86-interrupt" fn mouse_interrupt(_frame: InterruptStackFrame) {
-    unsafe { LAPIC.try_get().unwrap().lock().end_of_interrupt() }
+    // a process that loops in user mode makes no system calls to be killed at
+    if stack_frame.code_segment & 3 == 3 {
+        crate::process::check_killed();
+    }
 }
 
-extern "x86-interrupt" fn syscall_handler(_frame: InterruptStackFrame) {
-    log::debug!("Syscall interrupt!");
+extern "x86-interrupt" fn mouse_interrupt(_frame: InterruptStackFrame) {
     unsafe { LAPIC.try_get().unwrap().lock().end_of_interrupt() }
 }
 
@@ -107,30 +109,3 @@ extern "x86-interrupt" fn lapic_error(_frame: InterruptStackFrame) {
 }
 
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn general_fault_handler(stack_frame: InterruptStackFrame, some_num: u64) {
-    log::error!("EXCEPTION: GENERAL FAULT\n{:#?}\n\n{}", stack_frame, some_num);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    loop {}
-}
@@ -0,0 +1,282 @@
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+
+use conquer_once::spin::OnceCell;
+use futures_util::StreamExt;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+use crate::cpu::exceptions::{fatal_exception, ExceptionFrame};
+use crate::emergency_println;
+use crate::task::timer::Interval;
+
+const CPUID_MCE: u32 = 1 << 7;
+const CPUID_MCA: u32 = 1 << 14;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+/// The `MCi_CTL`, `MCi_STATUS`, `MCi_ADDR` and `MCi_MISC` registers of bank
+/// `i` start at `IA32_MC0_CTL + 4 * i`.
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xff;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+/// Execution can be restarted at the saved instruction pointer.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// The saved instruction pointer is where the error happened.
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+
+/// How often the banks are polled for corrected errors, in timer ticks.
+const POLL_INTERVAL: u64 = 50;
+
+struct MachineCheck {
+    banks: u8,
+}
+
+static MCA: OnceCell<MachineCheck> = OnceCell::uninit();
+
+/// Enables machine check exceptions and error reporting in every bank, if
+/// the CPU supports the machine check architecture.
+///
+/// Errors already logged in the banks are from before the kernel started,
+/// e.g. the cause of the last reset, and are logged and cleared.
+pub fn init() {
+    let features = unsafe { __cpuid(1) }.edx;
+    if features & CPUID_MCE == 0 || features & CPUID_MCA == 0 {
+        log::info!("machine check architecture not supported");
+        return;
+    }
+
+    let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    let banks = (cap & MCG_CAP_COUNT) as u8;
+    unsafe {
+        if cap & MCG_CAP_CTL_P != 0 {
+            Msr::new(IA32_MCG_CTL).write(u64::MAX);
+        }
+        for bank in 0..banks {
+            if let Some(error) = BankError::read(bank) {
+                log::warn!("machine check error from before boot: {}", error);
+            }
+            Msr::new(bank_msr(bank, 0)).write(u64::MAX);
+            BankError::clear(bank);
+        }
+        Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+    }
+    MCA.init_once(|| MachineCheck { banks });
+    log::info!("machine check architecture enabled with {} banks", banks);
+}
+
+/// Returns the MSR `register` (0 = CTL, 1 = STATUS, 2 = ADDR, 3 = MISC) of
+/// `bank`.
+fn bank_msr(bank: u8, register: u32) -> u32 {
+    IA32_MC0_CTL + 4 * u32::from(bank) + register
+}
+
+/// An error logged in a machine check bank.
+#[derive(Debug, Clone, Copy)]
+pub struct BankError {
+    pub bank: u8,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    /// Reads the error logged in `bank`, if there is one.
+    fn read(bank: u8) -> Option<Self> {
+        let status = unsafe { Msr::new(bank_msr(bank, 1)).read() };
+        if status & STATUS_VAL == 0 {
+            return None;
+        }
+        let optional = |flag: u64, register: u32| {
+            (status & flag != 0).then(|| unsafe { Msr::new(bank_msr(bank, register)).read() })
+        };
+        Some(BankError {
+            bank,
+            status,
+            addr: optional(STATUS_ADDRV, 2),
+            misc: optional(STATUS_MISCV, 3),
+        })
+    }
+
+    /// Marks the error logged in `bank` as handled.
+    fn clear(bank: u8) {
+        unsafe { Msr::new(bank_msr(bank, 1)).write(0) };
+    }
+
+    /// Whether the hardware corrected the error.
+    pub fn is_corrected(&self) -> bool {
+        self.status & STATUS_UC == 0
+    }
+
+    /// Whether the error corrupted the state of the processor.
+    pub fn is_context_corrupt(&self) -> bool {
+        self.status & STATUS_PCC != 0
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        ErrorCode(self.status as u16)
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = if self.is_corrected() {
+            "corrected"
+        } else if self.is_context_corrupt() {
+            "uncorrected, processor context corrupt"
+        } else {
+            "uncorrected"
+        };
+        write!(f, "bank {}: {} ({}), status {:#018x}", self.bank, self.error_code(), severity, self.status)?;
+        if let Some(addr) = self.addr {
+            write!(f, ", addr {:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {:#x}", misc)?;
+        }
+        if self.status & STATUS_OVER != 0 {
+            write!(f, ", earlier errors were lost")?;
+        }
+        Ok(())
+    }
+}
+
+/// The architectural MCA error code in the low 16 bits of `MCi_STATUS`.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorCode(pub u16);
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const LEVELS: [&str; 4] = ["L0", "L1", "L2", "generic"];
+        const TYPES: [&str; 4] = ["instruction", "data", "generic", "reserved"];
+        const REQUESTS: [&str; 9] = [
+            "generic", "read", "write", "data read", "data write",
+            "instruction fetch", "prefetch", "eviction", "snoop",
+        ];
+        let request = |r: u16| REQUESTS.get(usize::from(r)).copied().unwrap_or("reserved");
+
+        let simple = match self.0 {
+            0x0000 => Some("no error"),
+            0x0001 => Some("unclassified error"),
+            0x0002 => Some("microcode ROM parity error"),
+            0x0003 => Some("external error"),
+            0x0004 => Some("FRC error"),
+            0x0005 => Some("internal parity error"),
+            0x0006 => Some("SMM handler code access violation"),
+            0x0400 => Some("internal timer error"),
+            _ => None,
+        };
+        if let Some(simple) = simple {
+            return f.write_str(simple);
+        }
+
+        // bit 12 of the compound codes only says whether corrected errors are filtered
+        let code = self.0 & !0x1000;
+        let level = LEVELS[usize::from(code & 3)];
+        if code & 0xf800 == 0x0800 {
+            const PARTICIPATION: [&str; 4] = ["originated", "responded to", "observed", "involved in"];
+            const SPACES: [&str; 4] = ["memory", "reserved", "I/O", "other"];
+            write!(
+                f,
+                "bus error at {}: processor {} a {} {} request{}",
+                level,
+                PARTICIPATION[usize::from(code >> 9 & 3)],
+                request(code >> 4 & 0xf),
+                SPACES[usize::from(code >> 2 & 3)],
+                if code & 0x100 != 0 { ", timed out" } else { "" }
+            )
+        } else if code & 0xff00 == 0x0100 {
+            write!(
+                f,
+                "{} {} cache error on a {} request",
+                level,
+                TYPES[usize::from(code >> 2 & 3)],
+                request(code >> 4 & 0xf)
+            )
+        } else if code & 0xff80 == 0x0080 {
+            const TRANSACTIONS: [&str; 8] = [
+                "generic", "read", "write", "address/command", "scrubbing",
+                "reserved", "reserved", "reserved",
+            ];
+            write!(f, "memory controller error on a {} transaction", TRANSACTIONS[usize::from(code >> 4 & 7)])?;
+            match code & 0xf {
+                0xf => Ok(()),
+                channel => write!(f, ", channel {}", channel),
+            }
+        } else if code & 0xfff0 == 0x0010 {
+            write!(f, "{} {} TLB error", level, TYPES[usize::from(code >> 2 & 3)])
+        } else if code & 0xfffc == 0x000c {
+            write!(f, "{} cache hierarchy error", level)
+        } else if code & 0xfc00 == 0x0400 {
+            write!(f, "internal unclassified error")
+        } else {
+            write!(f, "unknown error code {:#06x}", self.0)
+        }
+    }
+}
+
+/// Handles a machine check exception: logs every bank that holds an error
+/// and resumes if all of them were corrected and execution can be restarted.
+///
+/// Like the rest of the exception path, this only uses the emergency
+/// printing path and does not allocate.
+pub fn handle_machine_check(frame: &mut ExceptionFrame) {
+    let Ok(mca) = MCA.try_get() else {
+        fatal_exception(frame, format_args!("machine check architecture not enabled"));
+    };
+
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    emergency_println!(
+        "MACHINE CHECK at {:#x}{}",
+        frame.rip,
+        if mcg_status & MCG_STATUS_EIPV != 0 { " (caused by this instruction)" } else { "" }
+    );
+    let mut recoverable = mcg_status & MCG_STATUS_RIPV != 0;
+    for bank in 0..mca.banks {
+        if let Some(error) = BankError::read(bank) {
+            emergency_println!("  {}", error);
+            recoverable &= error.is_corrected();
+        }
+    }
+    // the banks are left as they are so firmware can see them after the reset
+    if !recoverable {
+        fatal_exception(frame, format_args!("uncorrected machine check"));
+    }
+
+    for bank in 0..mca.banks {
+        BankError::clear(bank);
+    }
+    // clears MCIP, a machine check while it is set shuts the processor down
+    unsafe { Msr::new(IA32_MCG_STATUS).write(0) };
+}
+
+/// Periodically logs and clears the errors that the hardware corrected
+/// without raising a machine check exception.
+pub async fn poll_corrected_errors() {
+    let Ok(mca) = MCA.try_get() else {
+        return;
+    };
+    let mut interval = Interval::new(POLL_INTERVAL);
+    while interval.next().await.is_some() {
+        for bank in 0..mca.banks {
+            match BankError::read(bank) {
+                Some(error) if error.is_corrected() => {
+                    log::warn!("corrected machine check error: {}", error);
+                    BankError::clear(bank);
+                }
+                // uncorrected errors are left to the machine check handler
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,11 +1,39 @@
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+pub mod backtrace;
+pub mod exceptions;
+pub mod fpu;
 pub mod gdt;
 pub mod interrupts;
+pub mod mca;
+pub mod user;
 
 pub fn init() {
     gdt::init_gdt();
     log::debug!("init'd gdt");
     interrupts::init_idt();
     log::debug!("init'd idt");
+    mca::init();
+    fpu::init();
 //    x86_64::instructions::interrupts::enable();
     log::debug!("enabled interrupts");
 }
+
+/// Switches to the stack ending at `stack_top` and calls `entry` on it. The
+/// current stack is abandoned.
+///
+/// This function is unsafe because the caller must guarantee that the stack
+/// is mapped and stays alive for as long as `entry` runs.
+pub unsafe fn switch_stack(stack_top: VirtAddr, entry: extern "C" fn() -> !) -> ! {
+    // a null frame pointer ends backtraces at `entry`
+    asm!(
+        "mov rsp, {stack_top}",
+        "xor rbp, rbp",
+        "call {entry}",
+        stack_top = in(reg) stack_top.as_u64(),
+        entry = in(reg) entry,
+        options(noreturn),
+    );
+}
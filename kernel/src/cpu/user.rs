@@ -0,0 +1,194 @@
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+
+use crate::cpu::{exceptions, gdt};
+
+/// Why a run of user code returned to the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// The program called `exit`.
+    Exited(i32),
+    /// The program was killed by the kernel.
+    Killed,
+    /// The program raised an exception the kernel could not resolve.
+    Fault {
+        vector: u64,
+        error_code: u64,
+        rip: u64,
+        /// The faulting address, for page faults.
+        addr: Option<u64>,
+    },
+}
+
+/// The state of the user run in progress, kept on the stack of [`run`].
+struct UserRun {
+    /// Kernel stack pointer with the callee-saved registers on it.
+    saved_rsp: u64,
+    exit: Option<UserExit>,
+}
+
+static ACTIVE_RUN: AtomicPtr<UserRun> = AtomicPtr::new(ptr::null_mut());
+/// The stack set by [`set_kernel_stack`], or 0.
+static KERNEL_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+/// The user run and kernel stack of a thread, saved and restored by thread
+/// switches so that a thread preempted in user mode leaves it the same way
+/// it entered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserState {
+    run: usize,
+    kernel_stack: u64,
+}
+
+impl UserState {
+    /// Returns the state of the thread that is running.
+    pub fn current() -> Self {
+        UserState {
+            run: ACTIVE_RUN.load(Ordering::SeqCst) as usize,
+            kernel_stack: KERNEL_STACK_TOP.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Makes this the state of the thread that is running.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// state belongs to the thread that is about to run.
+    pub unsafe fn restore(self) {
+        ACTIVE_RUN.store(self.run as *mut UserRun, Ordering::SeqCst);
+        if self.kernel_stack != 0 {
+            set_kernel_stack(VirtAddr::new(self.kernel_stack));
+        }
+    }
+}
+
+// user_enter(saved_rsp: *mut u64, entry, stack_top, code_selector, data_selector)
+//
+// Saves the kernel's callee-saved registers and flags, points the kernel
+// stack for user-mode entries at what is left of the current stack, and
+// `iretq`s to `entry` with interrupts enabled and every other register
+// cleared. `user_leave` later returns from this call.
+global_asm!(
+    ".global user_enter",
+    "user_enter:",
+    "pushfq",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov r12, rsi",
+    "mov r13, rdx",
+    "mov r14, rcx",
+    "mov r15, r8",
+    "mov rdi, rsp",
+    "call {set_kernel_stack}",
+    "push r15",
+    "push r13",
+    "push 0x202",
+    "push r14",
+    "push r12",
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    "",
+    // user_leave(saved_rsp) -> !
+    ".global user_leave",
+    "user_leave:",
+    "mov rsp, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "popfq",
+    "ret",
+    set_kernel_stack = sym set_kernel_stack_raw,
+);
+
+extern "C" {
+    fn user_enter(saved_rsp: *mut u64, entry: u64, stack_top: u64, code_selector: u64, data_selector: u64);
+    fn user_leave(saved_rsp: u64) -> !;
+}
+
+extern "C" fn set_kernel_stack_raw(stack_top: u64) {
+    set_kernel_stack(VirtAddr::new(stack_top));
+}
+
+/// Sets the stack that interrupts, exceptions and system calls from user
+/// mode run on.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    KERNEL_STACK_TOP.store(stack_top.as_u64(), Ordering::SeqCst);
+    gdt::set_kernel_stack(stack_top);
+    crate::syscall::set_kernel_stack(stack_top);
+}
+
+/// Runs the user code at `entry` in ring 3, on the user stack ending at
+/// `stack_top`, until it exits or faults.
+///
+/// Interrupts, exceptions and system calls from user mode run on the rest
+/// of the calling kernel stack while the user code runs.
+///
+/// This function is unsafe because the caller must guarantee that `entry`
+/// and the stack are mapped user accessible in the active address space.
+pub unsafe fn run(entry: VirtAddr, stack_top: VirtAddr) -> UserExit {
+    let mut run = UserRun {
+        saved_rsp: 0,
+        exit: None,
+    };
+    let previous = ACTIVE_RUN.swap(&mut run, Ordering::SeqCst);
+    assert!(previous.is_null(), "user code is already running");
+
+    let selectors = gdt::selectors();
+    user_enter(
+        &mut run.saved_rsp,
+        entry.as_u64(),
+        stack_top.as_u64(),
+        u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0),
+    );
+    // `user_leave` wrote through the pointer in `ACTIVE_RUN`
+    let exit = ptr::read_volatile(&run.exit).expect("left user mode without a reason");
+    if let UserExit::Fault { vector, error_code, rip, .. } = exit {
+        let (mnemonic, name) = exceptions::exception_name(vector);
+        log::warn!("user {} {} at {:#x}, error code {:#x}", mnemonic, name, rip, error_code);
+    }
+    exit
+}
+
+/// Returns whether user code is running, i.e. whether a fault or system
+/// call from user mode can [`leave`].
+pub fn is_running() -> bool {
+    !ACTIVE_RUN.load(Ordering::SeqCst).is_null()
+}
+
+/// Abandons the user code that is running and makes [`run`] return `exit`.
+/// Called from system calls and exception handlers, whose frames are dropped.
+///
+/// Panics if no user code is running.
+pub fn leave(exit: UserExit) -> ! {
+    let run = ACTIVE_RUN.swap(ptr::null_mut(), Ordering::SeqCst);
+    assert!(!run.is_null(), "no user code is running");
+    unsafe {
+        (*run).exit = Some(exit);
+        user_leave((*run).saved_rsp)
+    }
+}
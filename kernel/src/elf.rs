@@ -0,0 +1,142 @@
+use core::convert::TryInto;
+
+/// File types.
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+/// Program header type of a loadable segment.
+pub const PT_LOAD: u32 = 1;
+/// Program header type naming the dynamic linker.
+pub const PT_INTERP: u32 = 3;
+
+/// Segment permission flags.
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a header or segment it describes.
+    Truncated,
+    BadMagic,
+    /// Not a 64-bit little endian x86_64 file.
+    Unsupported,
+}
+
+/// A read-only view of a 64-bit x86_64 ELF file in memory.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+}
+
+/// A program header, describing one segment of the file.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the ELF header of `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        let elf = ElfFile { data };
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || elf.u16_at(18) != EM_X86_64 {
+            return Err(ElfError::Unsupported);
+        }
+        let table_size = elf.program_header_count() * PROGRAM_HEADER_SIZE;
+        let table_end = (elf.u64_at(32) as usize).checked_add(table_size).ok_or(ElfError::Truncated)?;
+        if elf.u16_at(54) as usize != PROGRAM_HEADER_SIZE && elf.program_header_count() != 0 {
+            return Err(ElfError::Unsupported);
+        }
+        if table_end > data.len() {
+            return Err(ElfError::Truncated);
+        }
+        Ok(elf)
+    }
+
+    /// Returns the file type, e.g. [`ET_EXEC`].
+    pub fn kind(&self) -> u16 {
+        self.u16_at(16)
+    }
+
+    /// Returns the virtual address of the entry point.
+    pub fn entry(&self) -> u64 {
+        self.u64_at(24)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        let table = self.u64_at(32) as usize;
+        (0..self.program_header_count()).map(move |index| {
+            let base = table + index * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: self.u32_at(base),
+                flags: self.u32_at(base + 4),
+                offset: self.u64_at(base + 8),
+                vaddr: self.u64_at(base + 16),
+                file_size: self.u64_at(base + 32),
+                mem_size: self.u64_at(base + 40),
+                align: self.u64_at(base + 48),
+            }
+        })
+    }
+
+    /// Returns the file contents of a segment, or an error if the segment
+    /// lies outside the file.
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let start = header.offset as usize;
+        let end = start.checked_add(header.file_size as usize).ok_or(ElfError::Truncated)?;
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+
+    /// Returns the file offset of the program header table.
+    pub fn program_header_offset(&self) -> u64 {
+        self.u64_at(32)
+    }
+
+    pub fn program_header_count(&self) -> usize {
+        self.u16_at(56) as usize
+    }
+
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_le_bytes(self.data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.data[offset..offset + 8].try_into().unwrap())
+    }
+}
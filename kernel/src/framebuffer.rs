@@ -10,6 +10,10 @@ use noto_sans_mono_bitmap::{
 
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::memory::{self, vmm::VmaKind};
 
 use crate::framebuffer::font_constants::CHAR_RASTER_WIDTH;
 
@@ -50,6 +54,32 @@ pub fn init_global_fb(boot_info: &'static BootInfo) {
     FBWRITER.init_once(move || Mutex::new(FrameBufferWriter::new(fb.buffer_mut(), fb_info)));
 }
 
+/// Moves the framebuffer to a mapping of its own that uses 2 MiB pages where
+/// it can, instead of the 4 KiB pages the bootloader mapped it with. Called
+/// once the memory manager is up; the old mapping is left in place.
+pub fn remap() {
+    let Ok(writer) = FBWRITER.try_get() else {
+        return;
+    };
+    let mut writer = writer.lock();
+    let old = VirtAddr::from_ptr(writer.framebuffer.as_ptr());
+    let len = writer.framebuffer.len();
+    let phys_mem_offset = *memory::PHYS_MEM_OFFSET.try_get().unwrap();
+    let Some(phys) = (unsafe { memory::translate_addr(old, phys_mem_offset) }) else {
+        return;
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let result = unsafe { memory::map_physical(phys, len as u64, "framebuffer", VmaKind::Mmio, flags) };
+    if let Ok(new) = result {
+        writer.framebuffer = unsafe { core::slice::from_raw_parts_mut(new.as_mut_ptr(), len) };
+    }
+    drop(writer);
+    // the logger prints to the framebuffer, so only log once it is unlocked
+    if let Err(err) = result {
+        log::warn!("failed to remap the framebuffer: {:?}", err);
+    }
+}
+
 /// Returns the raster of the given char or the raster of [`font_constants::BACKUP_CHAR`].
 fn get_char_raster(c: char) -> RasterizedChar {
     fn get(c: char) -> Option<RasterizedChar> {
@@ -245,6 +275,24 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Prints to the framebuffer even if the writer lock is currently held.
+///
+/// Breaks the lock of whoever was printing, so this must only be used on
+/// paths that never return to the interrupted code (panics, fatal faults).
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    x86_64::instructions::interrupts::disable();
+    if let Ok(fb) = FBWRITER.try_get() {
+        if fb.is_locked() {
+            unsafe { fb.force_unlock() };
+        }
+        let mut fb_lock = fb.lock();
+        fb_lock.color = Color::Red;
+        let _ = fb_lock.write_fmt(args);
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! print {
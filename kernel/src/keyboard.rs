@@ -1,3 +1,4 @@
+use alloc::string::String;
 use conquer_once::spin::OnceCell;
 use futures_util::task::AtomicWaker;
 use core::{pin::Pin, task::{Context, Poll}};
@@ -61,6 +62,7 @@ pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard: Keyboard<layouts::Us104Key, ScancodeSet1> =
         Keyboard::new(HandleControl::Ignore);
+    let mut line = String::new();
 
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
@@ -69,12 +71,20 @@ pub async fn print_keypresses() {
                     DecodedKey::Unicode(character) => match character {
 
                         '\u{0008}' => {
-                            x86_64::instructions::interrupts::without_interrupts(|| {
-                                crate::framebuffer::FBWRITER.try_get().unwrap().lock().back_space();
-                            })
+                            if line.pop().is_some() {
+                                x86_64::instructions::interrupts::without_interrupts(|| {
+                                    crate::framebuffer::FBWRITER.try_get().unwrap().lock().back_space();
+                                })
+                            }
                         },
+                        '\n' => {
+                            crate::println!();
+                            crate::shell::run_command(&line);
+                            line.clear();
+                        }
                         c => {
-                            if c.is_alphanumeric(){
+                            if c.is_ascii_graphic() || c == ' ' {
+                                line.push(c);
                                 crate::print!("{}", character);
                             }
                         }
@@ -0,0 +1,260 @@
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::elf::{ElfError, ElfFile, ProgramHeader, ET_DYN, ET_EXEC, PT_INTERP};
+use crate::memory::address_space::{AddressSpace, USER_MAP_END, USER_START};
+use crate::memory::{MapError, PHYS_MEM_OFFSET};
+
+/// Where position independent executables are loaded.
+const PIE_BASE: u64 = 0x0000_0000_1000_0000;
+/// The user stack ends here, at the end of what user mappings may use.
+const USER_STACK_TOP: u64 = USER_MAP_END;
+const USER_STACK_SIZE: u64 = 256 * 1024; // 256 KiB
+/// Segments must end below the user stack.
+const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_SIZE;
+/// Most bytes the arguments, environment and auxiliary vector may take up
+/// at the top of the stack.
+const MAX_ARGS_SIZE: usize = 32 * 1024; // 32 KiB
+
+// auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// The file is neither an executable nor a position independent one.
+    NotExecutable,
+    /// The program needs a dynamic linker, which does not exist yet.
+    Dynamic,
+    /// A segment is larger in the file than in memory, or lies outside the
+    /// user half or in the user stack.
+    BadSegment,
+    /// Two segments share a page, and one of them is writable while the
+    /// other is executable.
+    ConflictingPermissions,
+    /// The arguments and environment do not fit into [`MAX_ARGS_SIZE`].
+    ArgumentsTooLarge,
+    Map(MapError),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(err: MapError) -> Self {
+        LoadError::Map(err)
+    }
+}
+
+/// A program loaded into its own address space, ready to run.
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    /// The initial stack pointer, pointing at `argc`.
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads the static or position independent ELF64 executable in `data`
+/// into a new address space, with `argv` and `envp` on its stack as the
+/// System V ABI lays them out.
+///
+/// Every `PT_LOAD` segment is copied into fresh frames, mapped writable and
+/// executable only as its flags allow, with the rest of its memory size
+/// (the `.bss`) zeroed.
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, LoadError> {
+    let elf = ElfFile::parse(data)?;
+    let bias = match elf.kind() {
+        ET_EXEC => 0,
+        ET_DYN => PIE_BASE,
+        _ => return Err(LoadError::NotExecutable),
+    };
+    if elf.program_headers().any(|header| header.kind == PT_INTERP) {
+        return Err(LoadError::Dynamic);
+    }
+
+    let mut address_space = AddressSpace::new()?;
+    let mut program_headers = None;
+    for segment in elf.program_headers().filter(ProgramHeader::is_load) {
+        let start = segment.vaddr.checked_add(bias).ok_or(LoadError::BadSegment)?;
+        let end = start.checked_add(segment.mem_size).ok_or(LoadError::BadSegment)?;
+        if segment.file_size > segment.mem_size || start < USER_START || end > USER_STACK_BOTTOM {
+            return Err(LoadError::BadSegment);
+        }
+        if segment.mem_size == 0 {
+            continue;
+        }
+
+        let mut flags = PageTableFlags::empty();
+        if segment.is_writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.is_executable() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        map_pages(&mut address_space, VirtAddr::new(start), segment.mem_size, flags)?;
+        write_bytes(&mut address_space, VirtAddr::new(start), elf.segment_data(&segment)?)?;
+
+        // the program headers are usually part of the first segment
+        let table = elf.program_header_offset();
+        if segment.offset <= table && table < segment.offset + segment.file_size {
+            program_headers = Some(start + (table - segment.offset));
+        }
+    }
+
+    let entry = elf.entry().checked_add(bias).ok_or(LoadError::NotExecutable)?;
+    let auxv = [
+        (AT_PHDR, program_headers.unwrap_or(0)),
+        (AT_PHENT, 56),
+        (AT_PHNUM, elf.program_header_count() as u64),
+        (AT_PAGESZ, Size4KiB::SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, entry),
+    ];
+    let stack_pointer = set_up_stack(&mut address_space, argv, envp, &auxv)?;
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// Reserves the user stack and writes the initial process stack to its top:
+/// `argc`, the `argv` and `envp` pointer arrays, the auxiliary vector, and
+/// above them the strings they point to. Returns the stack pointer.
+fn set_up_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, LoadError> {
+    let stack_bottom = VirtAddr::new(USER_STACK_BOTTOM);
+    address_space.map_anonymous(stack_bottom, USER_STACK_SIZE, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+
+    let strings_size = argv.iter().chain(envp).map(|s| s.len() + 1).sum::<usize>() + 16;
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 2);
+    if strings_size + words * 8 + 16 > MAX_ARGS_SIZE {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+    let strings_start = (USER_STACK_TOP - strings_size as u64) & !15;
+    let stack_pointer = (strings_start - words as u64 * 8) & !15;
+
+    // build everything between the stack pointer and the top in one buffer
+    let mut stack = Vec::with_capacity((USER_STACK_TOP - stack_pointer) as usize);
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut push_string = |s: &str| {
+        let addr = strings_start + strings.len() as u64;
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+        addr
+    };
+    let argv: Vec<u64> = argv.iter().map(|s| push_string(s)).collect();
+    let envp: Vec<u64> = envp.iter().map(|s| push_string(s)).collect();
+    let random = strings_start + strings.len() as u64;
+    strings.extend_from_slice(&random_bytes());
+
+    let mut push_word = |word: u64| stack.extend_from_slice(&word.to_le_bytes());
+    push_word(argv.len() as u64);
+    argv.iter().for_each(|&addr| push_word(addr));
+    push_word(0);
+    envp.iter().for_each(|&addr| push_word(addr));
+    push_word(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_RANDOM, random), (AT_NULL, 0)]) {
+        push_word(key);
+        push_word(value);
+    }
+    stack.resize((strings_start - stack_pointer) as usize, 0);
+    stack.extend_from_slice(&strings);
+
+    let stack_pointer = VirtAddr::new(stack_pointer);
+    map_pages(address_space, stack_pointer, stack.len() as u64, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)?;
+    write_bytes(address_space, stack_pointer, &stack)?;
+    Ok(stack_pointer)
+}
+
+/// Maps zeroed frames for every page of `start..start + size` that is not
+/// mapped yet. A page that already is, because two segments share it, gets
+/// the permissions of both, unless that would make it writable and
+/// executable when neither segment asked for it.
+fn map_pages(address_space: &mut AddressSpace, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), LoadError> {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size - 1));
+    for page in Page::range_inclusive(first, last) {
+        match address_space.translate(page) {
+            None => {
+                address_space.map_user_zeroed(page, flags)?;
+            }
+            Some((frame, existing)) => {
+                let mut combined = (existing | flags) & !PageTableFlags::NO_EXECUTE;
+                if existing.contains(PageTableFlags::NO_EXECUTE) && flags.contains(PageTableFlags::NO_EXECUTE) {
+                    combined |= PageTableFlags::NO_EXECUTE;
+                }
+                if is_writable_and_executable(combined)
+                    && !is_writable_and_executable(existing)
+                    && !is_writable_and_executable(flags)
+                {
+                    return Err(LoadError::ConflictingPermissions);
+                }
+                if combined != existing {
+                    address_space.unmap_user_page(page)?;
+                    address_space.map_user_page(page, frame, combined)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_writable_and_executable(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// Copies `bytes` to `start` in the (inactive) address space, whose pages
+/// must already be mapped.
+fn write_bytes(address_space: &mut AddressSpace, start: VirtAddr, bytes: &[u8]) -> Result<(), MapError> {
+    let phys_mem_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+    let mut written = 0;
+    while written < bytes.len() {
+        let addr = start + written as u64;
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let (frame, _): (PhysFrame, _) = address_space.translate(page).ok_or(MapError::NotMapped)?;
+        let offset = (addr - page.start_address()) as usize;
+        let len = (Size4KiB::SIZE as usize - offset).min(bytes.len() - written);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes[written..].as_ptr(),
+                (phys_mem_offset + frame.start_address().as_u64() + offset as u64).as_mut_ptr::<u8>(),
+                len,
+            );
+        }
+        written += len;
+    }
+    Ok(())
+}
+
+/// Returns the 16 bytes `AT_RANDOM` points to, which the C library seeds
+/// its stack protector with. Taken from the time stamp counter, so they are
+/// not suitable for anything stronger.
+fn random_bytes() -> [u8; 16] {
+    let mut state = unsafe { core::arch::x86_64::_rdtsc() };
+    let mut bytes = [0; 16];
+    for chunk in bytes.chunks_mut(8) {
+        // xorshift64*
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        chunk.copy_from_slice(&state.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes());
+    }
+    bytes
+}
@@ -49,3 +49,27 @@ impl log::Log for LockedLogger {
 
     fn flush(&self) {}
 }
+
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    crate::serial::_emergency_print(args);
+    crate::framebuffer::_emergency_print(args);
+}
+
+/// Prints to both the serial port and the framebuffer without allocating and
+/// without waiting for their locks. Meant for panics and fatal errors only.
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => {
+        $crate::logger::_emergency_print(format_args!($($arg)*))
+    };
+}
+
+/// Like [`emergency_print!`], appending a newline.
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n"));
+    ($fmt:expr) => ($crate::emergency_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::emergency_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 use core::panic::PanicInfo;
 
@@ -21,6 +22,14 @@ mod x2apic;
 mod acpi;
 mod keyboard;
 mod shell;
+mod elf;
+mod loader;
+mod process;
+mod programs;
+mod symbols;
+mod syscall;
+mod sync;
+mod thread;
 
 extern crate alloc;
 
@@ -28,10 +37,17 @@ use alloc::vec::Vec;
 
 pub static TIMER_FN: OnceCell<fn()> = OnceCell::uninit();
 
+/// Size of the guard-paged stack the kernel switches to once memory is set up.
+const KERNEL_STACK_SIZE: u64 = 100 * 1024; // 100 KiB
+
 const CONFIG: bootloader_api::BootloaderConfig = {
     let mut config = bootloader_api::BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(bootloader_api::config::Mapping::Dynamic);
+    // only used until `kernel_main` switches to a stack with a guard page
     config.kernel_stack_size = 100 * 1024; // 100 KiB
+    // keep the bootloader's mappings out of the range the kernel hands out itself
+    config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
+    config.mappings.dynamic_range_end = Some(memory::vmm::KERNEL_VMA_START - 1);
     config
 };
 
@@ -43,10 +59,33 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// What the kernel does once a panic or another fatal error was reported.
+#[allow(dead_code)]
+pub enum PanicPolicy {
+    /// Halt forever, leaving the report on screen.
+    Halt,
+    /// Exit QEMU through the `isa-debug-exit` device.
+    ExitQemu,
+}
+
+pub const PANIC_POLICY: PanicPolicy = PanicPolicy::Halt;
+
+pub fn apply_panic_policy() -> ! {
+    match PANIC_POLICY {
+        PanicPolicy::Halt => hlt_loop(),
+        PanicPolicy::ExitQemu => {
+            unsafe { x86_64::instructions::port::Port::<u32>::new(0xf4).write(0x11) };
+            hlt_loop();
+        }
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    log::error!("{info}");
-    hlt_loop();
+    // the logger allocates and takes locks, which may be what failed
+    emergency_println!("PANIC: {}", info);
+    cpu::backtrace::print(None, cpu::backtrace::ReturnAddresses::current());
+    apply_panic_policy();
 }
 
 fn init(boot_info: &'static BootInfo) {
@@ -54,13 +93,23 @@ fn init(boot_info: &'static BootInfo) {
     init_logger();
     memory::init(boot_info);
     allocator::init_heap();
+    framebuffer::remap();
     let apic = acpi::init(boot_info);
     x2apic::init(&apic);
     cpu::init();
+    syscall::init();
 }
 
 fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     init(boot_info);
+    let stack_top = memory::KernelStack::new(KERNEL_STACK_SIZE, "kernel main")
+        .expect("failed to allocate the kernel stack")
+        .leak();
+    unsafe { cpu::switch_stack(stack_top, kernel_main_stack) }
+}
+
+/// The rest of `kernel_main`, running on the kernel's own stack.
+extern "C" fn kernel_main_stack() -> ! {
     println!("init'd cpu");
     println!("hello from iron_kernel");
 
@@ -69,9 +118,22 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     vec.push(5);
 
     println!("DONE");
-    
+
+    thread::init();
     x86_64::instructions::interrupts::enable();
+    thread::check_preemption();
+
+    // the boot thread has nothing left to do but wait
+    let shell = thread::spawn("shell", run_shell).expect("failed to start the shell thread");
+    if let Err(err) = shell.join() {
+        log::error!("failed to join the shell thread: {:?}", err);
+    }
+    hlt_loop();
+}
 
+/// Runs the shell and the other async tasks, on a thread of their own so
+/// that they share the processor with every other thread.
+fn run_shell() {
     let shell = shell::Shell::init();
     TIMER_FN.init_once(|| {
         let func: fn() =  shell::Shell::update;
@@ -80,9 +142,8 @@ fn kernel_main(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
 
     let mut executor = task::executor::Executor::new();
     executor.spawn(task::Task::new(keyboard::print_keypresses()));
+    executor.spawn(task::Task::new(cpu::mca::poll_corrected_errors()));
     executor.run();
-
-    hlt_loop();
 }
 
 async fn async_number() -> u32 {
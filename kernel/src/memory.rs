@@ -1,219 +0,0 @@
-use bootloader_api::BootInfo;
-use conquer_once::spin::OnceCell;
-use spin::Mutex;
-use x86_64::{
-    structures::paging::PageTable,
-    VirtAddr,
-};
-
-pub static PHYS_MEM_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
-pub static FRAME_ALLOCATOR: OnceCell<Mutex<BootInfoFrameAllocator>> = OnceCell::uninit();
-pub static MAPPER: OnceCell<Mutex<OffsetPageTable>> = OnceCell::uninit();
-
-/// Returns a mutable reference to the active level 4 table.
-///
-/// This function is unsafe because the caller must guarantee that the
-/// complete physical memory is mapped to virtual memory at the passed
-/// `physical_memory_offset`. Also, this function must be only called once
-/// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
-    -> &'static mut PageTable
-{
-    use x86_64::registers::control::Cr3;
-
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-
-    &mut *page_table_ptr // unsafe
-}
-
-use x86_64::PhysAddr;
-
-/// Translates the given virtual address to the mapped physical address, or
-/// `None` if the address is not mapped.
-///
-/// This function is unsafe because the caller must guarantee that the
-/// complete physical memory is mapped to virtual memory at the passed
-/// `physical_memory_offset`.
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
-{
-    translate_addr_inner(addr, physical_memory_offset)
-}
-
-/// Private function that is called by `translate_addr`.
-///
-/// This function is safe to limit the scope of `unsafe` because Rust treats
-/// the whole body of unsafe functions as an unsafe block. This function must
-/// only be reachable through `unsafe fn` from outside of this module.
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
-{
-    use x86_64::structures::paging::page_table::FrameError;
-    use x86_64::registers::control::Cr3;
-
-    // read the active level 4 frame from the CR3 register
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let table_indexes = [
-        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
-    ];
-    let mut frame = level_4_table_frame;
-
-    // traverse the multi-level page table
-    for &index in &table_indexes {
-        // convert the frame into a page table reference
-        let virt = physical_memory_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe {&*table_ptr};
-
-        // read the page table entry and update `frame`
-        let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
-        };
-    }
-
-    // calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
-}
-
-use x86_64::structures::paging::OffsetPageTable;
-
-/// Initialize a new OffsetPageTable.
-///
-/// This function is unsafe because the caller must guarantee that the
-/// complete physical memory is mapped to virtual memory at the passed
-/// `physical_memory_offset`. Also, this function must be only called once
-/// to avoid aliasing `&mut` references (which is undefined behavior).
-
-/*pub unsafe fn init(physical_memory_offset: VirtAddr) {
-    PHYS_MEM_OFFSET.init_once(|| {
-        physical_memory_offset.clone()
-    });
-    let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
-}*/
-
-pub fn init(boot_info: &'static BootInfo) {
-    let offset = boot_info.physical_memory_offset.clone();
-    let phys_mem_offset = VirtAddr::new(offset.into_option().unwrap());
-    unsafe {
-        let page_table = active_level_4_table(phys_mem_offset);
-        let mapper = OffsetPageTable::new(page_table, phys_mem_offset);
-        let frame_allocator = BootInfoFrameAllocator::init(&boot_info.memory_regions);
-        MAPPER.init_once(|| Mutex::new(mapper));
-        FRAME_ALLOCATOR.init_once(|| Mutex::new(frame_allocator));
-        PHYS_MEM_OFFSET.init_once(|| phys_mem_offset);
-    }
-}
-
-pub struct EmptyFrameAllocator;
-
-unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        None
-    }
-}
-
-use x86_64::structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator};
-
-/// Creates an example mapping for the given page to frame `0xb8000`.
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    use x86_64::structures::paging::PageTableFlags as Flags;
-
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
-
-    let map_to_result = unsafe {
-        // FIXME: this is not safe, we do it only for testing
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();
-}
-
-use bootloader_api::info::MemoryRegions;
-use bootloader_api::info::MemoryRegionKind;
-
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
-}
-
-#[macro_export]
-macro_rules! map_physical_to_virtual {
-    ($phys_addr:expr, $virt_addr:expr) => {
-        use x86_64::{PhysAddr, VirtAddr};
-        use x86_64::structures::paging::{Mapper, mapper::MapToError};
-        use x86_64::structures::paging::{Page, Size4KiB, PageTableFlags, PhysFrame};
-        let result = unsafe {
-            $crate::memory::MAPPER.try_get().unwrap().lock().map_to(
-                Page::<Size4KiB>::containing_address(VirtAddr::new($virt_addr)),
-                PhysFrame::containing_address(PhysAddr::new($phys_addr)),
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                &mut *$crate::memory::FRAME_ALLOCATOR.try_get().unwrap().lock(),
-            )
-        };
-        match result {
-            Ok(flush) => flush.flush(),
-            Err(err) => match err {
-                MapToError::FrameAllocationFailed => {
-                    panic!("Failed to allocate frame!");
-                }
-                MapToError::PageAlreadyMapped(frame) => {
-                    log::debug!("Already mapped to frame: {:?}", frame);
-                }
-                MapToError::ParentEntryHugePage => {
-                    log::debug!("Already mapped to huge page!");
-                }
-            },
-        };
-    };
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
-unsafe impl Send for BootInfoFrameAllocator {}
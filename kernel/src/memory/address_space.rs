@@ -0,0 +1,708 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable,
+        Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::page_fault::FaultError;
+use super::swap::{self, Swap, SwapError, SWAPPED};
+use super::{zero_frame, BitmapFrameAllocator, MapError, FRAME_ALLOCATOR, PHYS_MEM_OFFSET};
+use crate::sync::NoPreemptMutex;
+
+/// Lowest address user mappings may use. The first pages are left unmapped
+/// to catch null pointer dereferences.
+pub const USER_START: u64 = 0x0000_0000_0040_0000;
+/// End (exclusive) of the user half of the address space.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// End (exclusive) of what user mappings may use. The last page stays
+/// unmapped, so that a `syscall` can never return to the non-canonical
+/// address right after it.
+pub const USER_MAP_END: u64 = USER_END - Size4KiB::SIZE;
+
+/// Index of the first level 4 entry that belongs to the kernel.
+const KERNEL_P4_START: usize = 256;
+/// Index of the first level 4 entry of the range the kernel hands out itself,
+/// see [`super::vmm::KERNEL_VMA_START`].
+const KERNEL_VMA_P4_START: usize = 384;
+
+/// The level 4 table the bootloader set up, which every address space copies
+/// its kernel half from.
+static KERNEL_PML4: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// Software-defined page table bit that marks a read-only mapping as
+/// copy-on-write. A write to such a page gets a private copy of the frame.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Region table of the address space currently loaded in CR3, or null while
+/// the kernel's own address space is active. Used by the page fault handler.
+static ACTIVE_REGIONS: AtomicPtr<NoPreemptMutex<Vec<UserRegion>>> = AtomicPtr::new(ptr::null_mut());
+
+/// Position of the clock hand used to pick pages for eviction, as an index
+/// into the pages of the anonymous regions of the address space being swept.
+static CLOCK_HAND: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of pages evicted at once when a fault finds no free frame.
+const EVICT_BATCH: usize = 16;
+
+/// Returns a pointer to the page table stored in `frame`.
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    let phys_mem_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+    (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn allocate_zeroed_table() -> Result<PhysFrame, MapError> {
+    let frame: PhysFrame<Size4KiB> = FRAME_ALLOCATOR
+        .try_get()
+        .unwrap()
+        .lock()
+        .allocate_frame()
+        .ok_or(MapError::FrameAllocationFailed)?;
+    unsafe { (*table_ptr(frame)).zero() };
+    Ok(frame)
+}
+
+/// Records the kernel's level 4 table and makes sure every entry of the
+/// kernel's own virtual range points to a level 3 table.
+///
+/// Address spaces copy the kernel's level 4 entries when they are created, so
+/// those entries must never change afterwards. Creating all level 3 tables
+/// up front means later kernel mappings only touch the shared lower levels.
+pub fn init(level_4_table: &mut PageTable) {
+    let (frame, _) = Cr3::read();
+    KERNEL_PML4.init_once(|| frame);
+
+    for entry in level_4_table.iter_mut().skip(KERNEL_VMA_P4_START) {
+        if entry.is_unused() {
+            let table = allocate_zeroed_table().expect("failed to allocate kernel page table");
+            entry.set_frame(table, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+}
+
+/// A range of the user half whose pages are mapped to zeroed frames on first
+/// access.
+#[derive(Debug, Clone, Copy)]
+pub struct UserRegion {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl UserRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+/// A set of page tables with a private user half and the kernel half shared
+/// with every other address space.
+///
+/// Frames mapped into the user half are owned by the address space and are
+/// returned to the frame allocator together with its page tables on drop.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    /// Boxed so the page fault handler can keep a pointer to it while the
+    /// address space is active, even if the `AddressSpace` itself moves.
+    regions: Box<NoPreemptMutex<Vec<UserRegion>>>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half.
+    pub fn new() -> Result<Self, MapError> {
+        let level_4_frame = allocate_zeroed_table()?;
+        let kernel_pml4 = *KERNEL_PML4.try_get().unwrap();
+        unsafe {
+            let kernel_table = &*table_ptr(kernel_pml4);
+            let table = &mut *table_ptr(level_4_frame);
+            for index in KERNEL_P4_START..512 {
+                table[index] = kernel_table[index].clone();
+            }
+        }
+        Ok(AddressSpace {
+            level_4_frame,
+            regions: Box::new(NoPreemptMutex::new(Vec::new())),
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns a mapper for this address space's page tables.
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let phys_mem_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+        unsafe { OffsetPageTable::new(&mut *table_ptr(self.level_4_frame), phys_mem_offset) }
+    }
+
+    /// Maps `page` to `frame` in the user half. `USER_ACCESSIBLE` is added to
+    /// `flags`, and the address space takes ownership of `frame`.
+    pub fn map_user_page(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        check_user_page(page)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        let flush = unsafe { self.mapper().map_to(page, frame, flags, &mut *frame_allocator)? };
+        self.flush_if_active(flush);
+        Ok(())
+    }
+
+    /// Allocates a zeroed frame and maps it at `page` in the user half.
+    pub fn map_user_zeroed(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapError> {
+        let frame: PhysFrame = FRAME_ALLOCATOR
+            .try_get()
+            .unwrap()
+            .lock()
+            .allocate_frame()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        zero_frame(frame);
+        if let Err(err) = self.map_user_page(page, frame, flags) {
+            unsafe { FRAME_ALLOCATOR.try_get().unwrap().lock().deallocate_frame(frame) };
+            return Err(err);
+        }
+        Ok(frame)
+    }
+
+    /// Unmaps `page` from the user half and returns the frame that backed it.
+    /// The caller takes over ownership of the frame.
+    pub fn unmap_user_page(&mut self, page: Page) -> Result<PhysFrame, MapError> {
+        check_user_page(page)?;
+        let (frame, flush) = self.mapper().unmap(page).map_err(|_| MapError::NotMapped)?;
+        self.flush_if_active(flush);
+        Ok(frame)
+    }
+
+    /// Maps `frame`, which may already be mapped elsewhere, at `page` in the
+    /// user half and adds a reference to it. Writable mappings are made
+    /// copy-on-write, so the frame itself is never written through this
+    /// address space.
+    pub fn map_shared_page(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+        let flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+        } else {
+            flags
+        };
+        FRAME_ALLOCATOR.try_get().unwrap().lock().share(frame);
+        if let Err(err) = self.map_user_page(page, frame, flags) {
+            unsafe { FRAME_ALLOCATOR.try_get().unwrap().lock().deallocate_frame(frame) };
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of this address space that shares every user frame
+    /// with it. Writable pages become copy-on-write in both address spaces,
+    /// so the copy is cheap and the frames are only duplicated when written.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, MapError> {
+        let mut child = AddressSpace::new()?;
+        *child.regions.lock() = self.regions.lock().clone();
+
+        let mut mappings = Vec::new();
+        unsafe { collect_user_mappings(self.level_4_frame, 4, 0, &mut mappings) };
+
+        for (page, entry) in mappings {
+            if let Some(slot) = swap::swapped_slot(&entry) {
+                // pages that are swapped out get a private copy right away
+                let frame = child.map_user_zeroed(page, entry.flags() - SWAPPED)?;
+                swap::lock()
+                    .ok_or(MapError::SwapFailed)?
+                    .read_frame(slot, frame)
+                    .map_err(|_| MapError::SwapFailed)?;
+                continue;
+            }
+            let (frame, flags) = (PhysFrame::containing_address(entry.addr()), entry.flags());
+            let flags = if flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+                let cow_flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                if cow_flags != flags {
+                    unsafe { self.mapper().update_flags(page, cow_flags) }
+                        .map_err(|_| MapError::NotMapped)?
+                        .ignore();
+                }
+                cow_flags
+            } else {
+                flags
+            };
+            child.map_shared_page(page, frame, flags)?;
+        }
+
+        if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        }
+        Ok(child)
+    }
+
+    /// Reserves `size` bytes at `start` in the user half as anonymous memory.
+    /// Nothing is mapped until the pages are first accessed.
+    pub fn map_anonymous(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        let end = start.as_u64().checked_add(size).ok_or(MapError::InvalidRange)?;
+        if size == 0 || !start.is_aligned(Size4KiB::SIZE) || start.as_u64() < USER_START || end > USER_MAP_END {
+            return Err(MapError::InvalidRange);
+        }
+        let region = UserRegion {
+            start,
+            size: (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE,
+            flags: flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+        };
+
+        let mut regions = self.regions.lock();
+        if regions.iter().any(|r| r.start < region.end() && region.start < r.end()) {
+            return Err(MapError::Overlap);
+        }
+        regions.push(region);
+        Ok(())
+    }
+
+    /// Removes the anonymous region starting at `start` and frees the frames
+    /// of all pages in it that were touched.
+    pub fn unmap_anonymous(&mut self, start: VirtAddr) -> Result<(), MapError> {
+        let region = {
+            let mut regions = self.regions.lock();
+            let index = regions.iter().position(|r| r.start == start).ok_or(MapError::NotMapped)?;
+            regions.remove(index)
+        };
+
+        let first = Page::<Size4KiB>::containing_address(region.start);
+        let last = Page::<Size4KiB>::containing_address(region.end() - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            match self.unmap_user_page(page) {
+                Ok(frame) => unsafe { FRAME_ALLOCATOR.try_get().unwrap().lock().deallocate_frame(frame) },
+                Err(_) => self.discard_swapped(page),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes up to `count` cold pages of the anonymous regions to swap and
+    /// frees their frames. Returns the number of pages written.
+    ///
+    /// Pages are picked with the clock algorithm: a page that was accessed
+    /// since the hand last passed it only loses its accessed bit.
+    pub fn page_out(&mut self, count: usize) -> Result<usize, SwapError> {
+        let regions = self.regions.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        let mut swap = swap::lock().ok_or(SwapError::Disabled)?;
+        Ok(unsafe { evict(&regions, self.level_4_frame, &mut frame_allocator, &mut swap, count) })
+    }
+
+    /// Releases the swap slot of `page` if it is swapped out.
+    fn discard_swapped(&mut self, page: Page) {
+        if let Some(entry) = unsafe { leaf_entry(self.level_4_frame, page) } {
+            if let Some(slot) = swap::swapped_slot(entry) {
+                swap::lock().unwrap().free(slot);
+                entry.set_unused();
+            }
+        }
+    }
+
+    /// Returns the frame `page` is mapped to and the flags of the mapping.
+    pub fn translate(&mut self, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+        use x86_64::structures::paging::mapper::{MappedFrame, Translate, TranslateResult};
+
+        match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => Some((frame, flags)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether this address space is the one currently loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// This function is unsafe because the caller must guarantee that nothing
+    /// still references the user half of the previous address space, and that
+    /// this address space stays alive for as long as it is active.
+    pub unsafe fn activate(&self) {
+        let regions: *const NoPreemptMutex<Vec<UserRegion>> = &*self.regions;
+        ACTIVE_REGIONS.store(regions as *mut _, Ordering::SeqCst);
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    fn flush_if_active(&self, flush: x86_64::structures::paging::mapper::MapperFlush<Size4KiB>) {
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+    }
+}
+
+/// Switches back to the kernel's own address space.
+///
+/// This function is unsafe for the same reasons as [`AddressSpace::activate`].
+pub unsafe fn activate_kernel() {
+    Cr3::write(*KERNEL_PML4.try_get().unwrap(), Cr3Flags::empty());
+    ACTIVE_REGIONS.store(ptr::null_mut(), Ordering::SeqCst);
+}
+
+/// The address space loaded on the processor, saved and restored by thread
+/// switches so that every thread keeps running in its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveSpace {
+    level_4_frame: PhysFrame,
+    regions: usize,
+}
+
+impl ActiveSpace {
+    /// Returns the kernel's own address space.
+    pub fn kernel() -> Self {
+        ActiveSpace {
+            level_4_frame: *KERNEL_PML4.try_get().unwrap(),
+            regions: 0,
+        }
+    }
+
+    /// Returns the address space that is active right now.
+    pub fn current() -> Self {
+        ActiveSpace {
+            level_4_frame: Cr3::read().0,
+            regions: ACTIVE_REGIONS.load(Ordering::SeqCst) as usize,
+        }
+    }
+
+    /// Makes this address space active again, if it is not already.
+    ///
+    /// This function is unsafe for the same reasons as [`AddressSpace::activate`].
+    pub unsafe fn restore(self) {
+        ACTIVE_REGIONS.store(self.regions as *mut _, Ordering::SeqCst);
+        if Cr3::read().0 != self.level_4_frame {
+            Cr3::write(self.level_4_frame, Cr3Flags::empty());
+        }
+    }
+}
+
+/// Checks that user mode may access every byte of `start..start + len` in
+/// the active address space, with write access if `write` is set. A page
+/// qualifies if it is mapped user accessible, or if it lies inside an
+/// anonymous region and an access would fault it in.
+///
+/// This is what makes it safe for the kernel to touch a user buffer: an
+/// access to the range can then only fault in ways the page fault handler
+/// resolves.
+pub fn is_user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    let end = match start.as_u64().checked_add(len) {
+        Some(end) if start.as_u64() >= USER_START && end <= USER_MAP_END => end,
+        _ => return false,
+    };
+    if len == 0 {
+        return true;
+    }
+
+    let regions = ACTIVE_REGIONS.load(Ordering::SeqCst);
+    let regions = (!regions.is_null()).then(|| unsafe { &*regions }.lock());
+    let level_4_frame = Cr3::read().0;
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        let mapped = unsafe { leaf_entry(level_4_frame, page) }.map_or(false, |entry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+                && (!write || flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE))
+        });
+        mapped
+            || regions.as_ref().map_or(false, |regions| {
+                regions.iter().any(|region| {
+                    region.contains(page.start_address())
+                        && (!write || region.flags.contains(PageTableFlags::WRITABLE))
+                })
+            })
+    })
+}
+
+/// Resolves a fault on a not-present page of the active user half, if the
+/// address lies inside an anonymous region: a swapped out page is read back
+/// from swap, any other page is mapped to a zeroed frame. When no frame is
+/// free, cold pages are evicted to swap first.
+///
+/// Runs in the page fault handler, so it only tries the locks it needs.
+pub(super) fn handle_user_fault(addr: VirtAddr, write: bool) -> Result<(), FaultError> {
+    let regions = ACTIVE_REGIONS.load(Ordering::SeqCst);
+    if regions.is_null() {
+        return Err(FaultError::NoRegion);
+    }
+    let regions = unsafe { &*regions }.try_lock().ok_or(FaultError::Locked)?;
+    let region = regions.iter().find(|r| r.contains(addr)).ok_or(FaultError::NoRegion)?;
+    if write && !region.flags.contains(PageTableFlags::WRITABLE) {
+        return Err(FaultError::AccessViolation);
+    }
+
+    let level_4_frame = Cr3::read().0;
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().try_lock().ok_or(FaultError::Locked)?;
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => {
+            let mut swap = swap::try_lock().ok_or(FaultError::OutOfMemory)?;
+            unsafe { evict(&regions, level_4_frame, &mut frame_allocator, &mut swap, EVICT_BATCH) };
+            frame_allocator.allocate_frame().ok_or(FaultError::OutOfMemory)?
+        }
+    };
+
+    if let Some(entry) = unsafe { leaf_entry(level_4_frame, page) } {
+        if let Some(slot) = swap::swapped_slot(entry) {
+            let result = swap::try_lock()
+                .ok_or(FaultError::Locked)
+                .and_then(|mut swap| {
+                    swap.read_frame(slot, frame).map_err(|_| FaultError::SwapFailed)?;
+                    swap.free(slot);
+                    Ok(())
+                });
+            if let Err(err) = result {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(err);
+            }
+            swap::set_swapped_in(entry, frame);
+            tlb::flush(page.start_address());
+            return Ok(());
+        }
+    }
+    zero_frame(frame);
+
+    let phys_mem_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+    let mut mapper = unsafe { OffsetPageTable::new(&mut *table_ptr(level_4_frame), phys_mem_offset) };
+    match unsafe { mapper.map_to(page, frame, region.flags, &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(FaultError::OutOfMemory)
+        }
+    }
+}
+
+/// Resolves a write fault on a copy-on-write page of the active user half.
+///
+/// If the faulting address space holds the last reference to the frame, the
+/// page is simply made writable again. Otherwise the frame is copied and the
+/// page remapped to the private copy.
+pub(super) fn handle_cow_fault(addr: VirtAddr) -> Result<(), FaultError> {
+    use x86_64::structures::paging::mapper::{MappedFrame, Translate, TranslateResult};
+
+    let phys_mem_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+    let mut mapper = unsafe { OffsetPageTable::new(&mut *table_ptr(Cr3::read().0), phys_mem_offset) };
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return Err(FaultError::ProtectionViolation),
+    };
+    let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().try_lock().ok_or(FaultError::Locked)?;
+    if frame_allocator.ref_count(frame) <= 1 {
+        unsafe { mapper.update_flags(page, writable) }
+            .map_err(|_| FaultError::ProtectionViolation)?
+            .flush();
+        return Ok(());
+    }
+
+    let copy = frame_allocator.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (phys_mem_offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+            (phys_mem_offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        );
+        // only the entry changes, so the page tables can be updated in place
+        let (_, flush) = mapper.unmap(page).map_err(|_| FaultError::ProtectionViolation)?;
+        flush.ignore();
+        mapper
+            .map_to(page, copy, writable, &mut *frame_allocator)
+            .map_err(|_| FaultError::OutOfMemory)?
+            .flush();
+        frame_allocator.deallocate_frame(frame);
+    }
+    Ok(())
+}
+
+/// Writes up to `count` cold pages of `regions` in the address space with
+/// the given level 4 table to swap. Returns the number of pages written.
+///
+/// This function is unsafe because `regions` must belong to that address
+/// space and its page tables must not be modified concurrently.
+unsafe fn evict(
+    regions: &[UserRegion],
+    level_4_frame: PhysFrame,
+    frame_allocator: &mut BitmapFrameAllocator,
+    swap: &mut Swap,
+    count: usize,
+) -> usize {
+    let total_pages: usize = regions.iter().map(|r| (r.size / Size4KiB::SIZE) as usize).sum();
+    let mut evicted = 0;
+
+    // two full sweeps, since the first may only clear accessed bits
+    for _ in 0..total_pages * 2 {
+        if evicted == count {
+            break;
+        }
+        let mut index = CLOCK_HAND.fetch_add(1, Ordering::Relaxed) % total_pages;
+        let region = regions
+            .iter()
+            .find(|r| {
+                let pages = (r.size / Size4KiB::SIZE) as usize;
+                let found = index < pages;
+                if !found {
+                    index -= pages;
+                }
+                found
+            })
+            .unwrap();
+        let page = Page::<Size4KiB>::containing_address(region.start) + index as u64;
+
+        let entry = match leaf_entry(level_4_frame, page) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let flags = entry.flags();
+        let frame = PhysFrame::containing_address(entry.addr());
+        // shared frames stay in memory, the other users may need them
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(COPY_ON_WRITE)
+            || frame_allocator.ref_count(frame) > 1
+        {
+            continue;
+        }
+        if flags.contains(PageTableFlags::ACCESSED) {
+            entry.set_flags(flags - PageTableFlags::ACCESSED);
+            tlb::flush(page.start_address());
+            continue;
+        }
+
+        match swap.write_frame(frame) {
+            Ok(slot) => {
+                swap::set_swapped(entry, slot);
+                tlb::flush(page.start_address());
+                frame_allocator.deallocate_frame(frame);
+                evicted += 1;
+            }
+            Err(_) => break,
+        }
+    }
+    evicted
+}
+
+/// Returns the level 1 entry for `page` in the given page tables, or `None`
+/// if one of the higher level tables is missing.
+unsafe fn leaf_entry(level_4_frame: PhysFrame, page: Page) -> Option<&'static mut PageTableEntry> {
+    let mut table = &mut *table_ptr(level_4_frame);
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = &mut *table_ptr(PhysFrame::containing_address(table[index].addr()));
+    }
+    Some(&mut table[page.p1_index()])
+}
+
+/// Appends every 4 KiB user mapping reachable from the table in `frame` (at
+/// paging `level`, covering addresses from `base`) to `mappings`, including
+/// pages that are swapped out.
+unsafe fn collect_user_mappings(
+    frame: PhysFrame,
+    level: u8,
+    base: u64,
+    mappings: &mut Vec<(Page, PageTableEntry)>,
+) {
+    let table = &*table_ptr(frame);
+    let entries = if level == 4 { KERNEL_P4_START } else { 512 };
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+
+    for (index, entry) in table.iter().enumerate().take(entries) {
+        if entry.is_unused() {
+            continue;
+        }
+        let addr = base + index as u64 * entry_size;
+        let child = PhysFrame::containing_address(entry.addr());
+        if level == 1 {
+            mappings.push((Page::containing_address(VirtAddr::new(addr)), entry.clone()));
+        } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            collect_user_mappings(child, level - 1, addr, mappings);
+        }
+    }
+}
+
+fn check_user_page(page: Page) -> Result<(), MapError> {
+    let addr = page.start_address().as_u64();
+    if (USER_START..USER_MAP_END).contains(&addr) {
+        Ok(())
+    } else {
+        Err(MapError::InvalidRange)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let regions: *const NoPreemptMutex<Vec<UserRegion>> = &*self.regions;
+        let _ = ACTIVE_REGIONS.compare_exchange(regions as *mut _, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        unsafe {
+            free_user_tables(self.level_4_frame, 4, &mut *frame_allocator);
+        }
+    }
+}
+
+/// Frees every frame reachable from the user half of the table in `frame`
+/// (at paging `level`), then the table itself.
+unsafe fn free_user_tables(
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table = &mut *table_ptr(frame);
+    // only the lower half of the level 4 table belongs to this address space
+    let entries = if level == 4 { KERNEL_P4_START } else { 512 };
+
+    for entry in table.iter_mut().take(entries) {
+        if entry.is_unused() {
+            continue;
+        }
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            if let Some(slot) = swap::swapped_slot(entry) {
+                swap::lock().unwrap().free(slot);
+            }
+            entry.set_unused();
+            continue;
+        }
+        let child = PhysFrame::containing_address(entry.addr());
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // user mappings only ever use 4 KiB frames
+            frame_allocator.deallocate_frame(child);
+        } else {
+            free_user_tables(child, level - 1, frame_allocator);
+        }
+        entry.set_unused();
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
@@ -0,0 +1,270 @@
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::vmm::{self, VmaKind};
+use super::{set_phys_cache_mode, CacheMode, MapError, FRAME_ALLOCATOR, MAPPER};
+
+/// Physically contiguous memory mapped into the kernel's address space.
+///
+/// Unless the region is write-back, the physical memory mapping of its frames
+/// is switched to the same cache mode while the region lives, so that the
+/// frames are never cached through one mapping and not through the other.
+///
+/// The frames are returned to the frame allocator and the mapping is removed
+/// when the region is dropped.
+#[derive(Debug)]
+pub struct DmaRegion {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+    cache_mode: CacheMode,
+}
+
+impl DmaRegion {
+    /// Allocates at least `size` bytes of zeroed, physically contiguous memory
+    /// whose physical address is aligned to `align`.
+    pub fn new(size: usize, align: usize, cache_mode: CacheMode) -> Result<Self, MapError> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(MapError::InvalidRange);
+        }
+        let page_count = (size as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        let size = (page_count * Size4KiB::SIZE) as usize;
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | cache_mode.flags();
+        let first_frame = FRAME_ALLOCATOR
+            .try_get()
+            .unwrap()
+            .lock()
+            .allocate_contiguous(page_count as usize, align as u64)
+            .ok_or(MapError::FrameAllocationFailed)?;
+        let virt = match vmm::allocate(size as u64, "dma", VmaKind::Dma, flags) {
+            Ok(virt) => virt,
+            Err(err) => {
+                unsafe {
+                    FRAME_ALLOCATOR
+                        .try_get()
+                        .unwrap()
+                        .lock()
+                        .deallocate_contiguous(first_frame, page_count as usize)
+                };
+                return Err(err);
+            }
+        };
+        let region = DmaRegion {
+            virt,
+            phys: first_frame.start_address(),
+            size,
+            cache_mode,
+        };
+        if cache_mode != CacheMode::WriteBack {
+            // the frames are freshly allocated, so nothing uses them yet
+            unsafe { set_phys_cache_mode(first_frame, page_count, cache_mode) }?;
+        }
+
+        let mut mapper = MAPPER.try_get().unwrap().lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        let frames = PhysFrame::range(first_frame, first_frame + page_count);
+        for (page, frame) in region.pages().zip(frames) {
+            // the pages mapped so far are unmapped again when `region` is dropped
+            let flush = unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }?;
+            flush.flush();
+        }
+        drop(frame_allocator);
+        drop(mapper);
+
+        unsafe { core::ptr::write_bytes(region.virt.as_mut_ptr::<u8>(), 0, size) };
+        Ok(region)
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The physical address to hand to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size) }
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::<Size4KiB>::containing_address(self.virt);
+        Page::range(first, first + self.size as u64 / Size4KiB::SIZE)
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        let page_count = self.size / Size4KiB::SIZE as usize;
+        let mut mapper = MAPPER.try_get().unwrap().lock();
+        for page in self.pages() {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+        drop(mapper);
+        vmm::free(self.virt);
+
+        let first_frame = PhysFrame::containing_address(self.phys);
+        if self.cache_mode != CacheMode::WriteBack {
+            // this can only fail at the frame that failed in `new`, and the
+            // frames from there on were never changed
+            let _ = unsafe {
+                set_phys_cache_mode(first_frame, page_count as u64, CacheMode::WriteBack)
+            };
+        }
+        unsafe {
+            FRAME_ALLOCATOR
+                .try_get()
+                .unwrap()
+                .lock()
+                .deallocate_contiguous(first_frame, page_count)
+        };
+    }
+}
+
+/// A value of type `T` in uncached, physically contiguous memory, e.g. a
+/// command list or a descriptor ring shared with a device.
+pub struct DmaBuffer<T> {
+    region: DmaRegion,
+    _value: PhantomData<T>,
+}
+
+impl<T> DmaBuffer<T> {
+    /// Moves `value` into a new buffer.
+    pub fn new(value: T) -> Result<Self, MapError> {
+        let align = mem::align_of::<T>().max(Size4KiB::SIZE as usize);
+        let region = DmaRegion::new(mem::size_of::<T>().max(1), align, CacheMode::Uncached)?;
+        unsafe { region.virt.as_mut_ptr::<T>().write(value) };
+        Ok(DmaBuffer {
+            region,
+            _value: PhantomData,
+        })
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.region.virt_addr()
+    }
+
+    /// The physical address to hand to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.region.phys_addr()
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.region.virt.as_ptr::<T>() }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.region.virt.as_mut_ptr::<T>() }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        unsafe { core::ptr::drop_in_place(self.region.virt.as_mut_ptr::<T>()) };
+    }
+}
+
+unsafe impl<T: Send> Send for DmaBuffer<T> {}
+
+/// Hands out equally sized blocks of one physically contiguous region, for
+/// drivers that need many small buffers like packet buffers or PRDs.
+pub struct DmaPool {
+    region: DmaRegion,
+    block_size: usize,
+    free: Mutex<Vec<usize>>,
+}
+
+/// A block borrowed from a [`DmaPool`]. It is returned to the pool on drop.
+pub struct DmaBlock<'a> {
+    pool: &'a DmaPool,
+    index: usize,
+}
+
+impl DmaPool {
+    /// Creates a pool of `count` blocks of `block_size` bytes, each aligned to
+    /// `align` bytes in physical memory.
+    pub fn new(block_size: usize, align: usize, count: usize) -> Result<Self, MapError> {
+        if block_size == 0 || count == 0 || !align.is_power_of_two() {
+            return Err(MapError::InvalidRange);
+        }
+        let block_size = (block_size + align - 1) / align * align;
+        let size = block_size.checked_mul(count).ok_or(MapError::InvalidRange)?;
+        let region = DmaRegion::new(size, align, CacheMode::Uncached)?;
+        Ok(DmaPool {
+            region,
+            block_size,
+            free: Mutex::new((0..count).rev().collect()),
+        })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Takes a zeroed block from the pool, or returns `None` if all blocks are
+    /// in use.
+    pub fn alloc(&self) -> Option<DmaBlock<'_>> {
+        let index = self.free.lock().pop()?;
+        let mut block = DmaBlock { pool: self, index };
+        block.as_mut_slice().fill(0);
+        Some(block)
+    }
+}
+
+impl DmaBlock<'_> {
+    fn offset(&self) -> usize {
+        self.index * self.pool.block_size
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.pool.region.virt_addr() + self.offset()
+    }
+
+    /// The physical address to hand to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.pool.region.phys_addr() + self.offset()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt_addr().as_ptr(), self.pool.block_size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.pool.block_size) }
+    }
+}
+
+impl Drop for DmaBlock<'_> {
+    fn drop(&mut self) {
+        self.pool.free.lock().push(self.index);
+    }
+}
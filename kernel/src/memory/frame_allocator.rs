@@ -0,0 +1,441 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// A snapshot of the frame allocator's counters.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Number of 4 KiB frames that were reported as usable by the bootloader.
+    pub total_frames: usize,
+    /// Number of 4 KiB frames that are currently free.
+    pub free_frames: usize,
+}
+
+impl FrameStats {
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+}
+
+/// The frames that belong to one NUMA node.
+#[derive(Debug, Clone)]
+pub struct MemoryNode {
+    pub id: u32,
+    /// Frame index ranges of the node.
+    ranges: Vec<Range<usize>>,
+    pub total_frames: usize,
+    pub free_frames: usize,
+    /// Frame index at which the next search on this node starts.
+    next_free: usize,
+}
+
+impl MemoryNode {
+    /// Groups `(node, base, length)` memory ranges by node. Call this before
+    /// locking the frame allocator, since it allocates.
+    pub fn from_ranges(ranges: impl Iterator<Item = (u32, u64, u64)>) -> Vec<MemoryNode> {
+        let mut nodes: Vec<MemoryNode> = Vec::new();
+        for (id, base, length) in ranges {
+            let start = (align_up(base, FRAME_SIZE) / FRAME_SIZE) as usize;
+            let end = ((base + length) / FRAME_SIZE) as usize;
+            if start >= end {
+                continue;
+            }
+            match nodes.iter_mut().find(|node| node.id == id) {
+                Some(node) => node.ranges.push(start..end),
+                None => nodes.push(MemoryNode {
+                    id,
+                    ranges: alloc::vec![start..end],
+                    total_frames: 0,
+                    free_frames: 0,
+                    next_free: 0,
+                }),
+            }
+        }
+        nodes.sort_unstable_by_key(|node| node.id);
+        nodes
+    }
+
+    fn contains(&self, index: usize) -> bool {
+        self.ranges.iter().any(|range| range.contains(&index))
+    }
+}
+
+/// A physical frame allocator that keeps one bit per 4 KiB frame.
+///
+/// A set bit means the frame is in use (or not usable at all). The bitmap
+/// itself is stored in the first usable region that is large enough to hold
+/// it and is accessed through the physical memory offset mapping, so the
+/// allocator works before the heap exists.
+///
+/// Next to the bitmap there is a reference count for every frame, so that a
+/// frame can be mapped into several address spaces (see [`share`]). A frame
+/// is only freed once its last reference is deallocated.
+///
+/// [`share`]: BitmapFrameAllocator::share
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Number of references to each allocated frame. `0` for free frames and
+    /// for frames that were never handed out by this allocator.
+    ref_counts: &'static mut [u16],
+    /// Number of frames described by `bitmap`.
+    frame_count: usize,
+    /// Word index at which the next single-frame search starts.
+    next_word: usize,
+    total_frames: usize,
+    free_frames: usize,
+    /// NUMA nodes and their frames, empty until [`set_nodes`] is called.
+    ///
+    /// [`set_nodes`]: BitmapFrameAllocator::set_nodes
+    nodes: Vec<MemoryNode>,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a frame allocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. All frames that are marked as `USABLE` in the
+    /// memory map must really be unused.
+    pub unsafe fn init(memory_map: &'static MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+        };
+
+        let max_addr = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (word_count * core::mem::size_of::<u64>()) as u64;
+        let metadata_bytes = bitmap_bytes + (frame_count * core::mem::size_of::<u16>()) as u64;
+
+        // place the bitmap and the reference counts at the start of the first
+        // usable region that can hold them
+        let bitmap_start = usable_regions()
+            .map(|r| align_up(r.start, FRAME_SIZE)..r.end)
+            .find(|r| r.start != 0 && r.end - r.start >= metadata_bytes)
+            .map(|r| r.start)
+            .expect("no usable region is large enough for the frame bitmap");
+
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, word_count);
+        bitmap.fill(u64::MAX);
+        let ref_counts_ptr: *mut u16 = (physical_memory_offset + bitmap_start + bitmap_bytes).as_mut_ptr();
+        let ref_counts = core::slice::from_raw_parts_mut(ref_counts_ptr, frame_count);
+        ref_counts.fill(0);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            ref_counts,
+            frame_count,
+            next_word: 0,
+            total_frames: 0,
+            free_frames: 0,
+            nodes: Vec::new(),
+        };
+
+        for region in usable_regions() {
+            let start = (align_up(region.start, FRAME_SIZE) / FRAME_SIZE) as usize;
+            let end = (region.end / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear_bit(index);
+            }
+            allocator.total_frames += end.saturating_sub(start);
+        }
+        allocator.free_frames = allocator.total_frames;
+
+        // never hand out the null frame, and reserve the frames backing the bitmap
+        allocator.reserve(0, 1);
+        let bitmap_frames = ((metadata_bytes + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        allocator.reserve((bitmap_start / FRAME_SIZE) as usize, bitmap_frames);
+
+        allocator
+    }
+
+    /// Returns the current frame counters.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            free_frames: self.free_frames,
+        }
+    }
+
+    /// Assigns frames to NUMA nodes, see [`MemoryNode::from_ranges`]. Frames
+    /// outside all nodes are still handed out, but only as a last resort.
+    ///
+    /// This must not allocate: the heap may need to take this lock to grow.
+    pub fn set_nodes(&mut self, mut nodes: Vec<MemoryNode>) {
+        for node in &mut nodes {
+            for range in &mut node.ranges {
+                range.end = range.end.min(self.frame_count);
+                range.start = range.start.min(range.end);
+            }
+            node.total_frames = node.ranges.iter().map(|range| range.len()).sum();
+            let bitmap = &*self.bitmap;
+            let is_used = |index: usize| bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0;
+            node.free_frames = node
+                .ranges
+                .iter()
+                .flat_map(|range| range.clone())
+                .filter(|&index| !is_used(index))
+                .count();
+        }
+        self.nodes = nodes;
+    }
+
+    /// Returns the NUMA nodes with their frame counters.
+    pub fn nodes(&self) -> &[MemoryNode] {
+        &self.nodes
+    }
+
+    /// Allocates a frame from the given NUMA node only.
+    pub fn allocate_on_node(&mut self, node: u32) -> Option<PhysFrame> {
+        let node_index = self.nodes.iter().position(|n| n.id == node)?;
+        let node = &self.nodes[node_index];
+        if node.free_frames == 0 {
+            return None;
+        }
+        // resume where the last search on the node stopped, and wrap around
+        let hint = node.next_free;
+        let after = node.ranges.iter().filter_map(|range| {
+            let start = range.start.max(hint);
+            (start < range.end).then(|| start..range.end)
+        });
+        let before = node.ranges.iter().filter_map(|range| {
+            let end = range.end.min(hint);
+            (range.start < end).then(|| range.start..end)
+        });
+        let index = after.chain(before).find_map(|range| self.find_free(range))?;
+        self.nodes[node_index].next_free = index + 1;
+        self.set_bit(index);
+        self.ref_counts[index] = 1;
+        self.free_frames -= 1;
+        self.account(index, -1);
+        Some(Self::frame_at(index))
+    }
+
+    /// Allocates a frame from `node`, or from the closest node that has a
+    /// free frame.
+    pub fn allocate_near(&mut self, node: u32) -> Option<PhysFrame> {
+        match super::numa::topology() {
+            Some(topology) => topology
+                .fallback_order(node)
+                .iter()
+                .find_map(|&node| self.allocate_on_node(node))
+                .or_else(|| self.allocate_single()),
+            None => self.allocate_single(),
+        }
+    }
+
+    /// Returns the first free frame in `range`, a bitmap word at a time.
+    fn find_free(&self, range: Range<usize>) -> Option<usize> {
+        let mut index = range.start;
+        while index < range.end {
+            let word_start = index / BITS_PER_WORD * BITS_PER_WORD;
+            // count the frames below `index` as used
+            let word = self.bitmap[index / BITS_PER_WORD] | ((1 << (index - word_start)) - 1);
+            if word != u64::MAX {
+                let free = word_start + word.trailing_ones() as usize;
+                return (free < range.end).then_some(free);
+            }
+            index = word_start + BITS_PER_WORD;
+        }
+        None
+    }
+
+    /// Adjusts the free frame counter of the node that frame `index` belongs
+    /// to. A freed frame below the node's search hint moves the hint back.
+    fn account(&mut self, index: usize, delta: isize) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.contains(index)) {
+            node.free_frames = (node.free_frames as isize + delta) as usize;
+            if delta > 0 {
+                node.next_free = node.next_free.min(index);
+            }
+        }
+    }
+
+    /// Allocates `count` physically contiguous 4 KiB frames whose first frame
+    /// is aligned to `align` bytes. Returns the first frame of the run.
+    pub fn allocate_contiguous(&mut self, count: usize, align: u64) -> Option<PhysFrame> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        let align_frames = (align.max(FRAME_SIZE) / FRAME_SIZE) as usize;
+
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            match (start..start + count).rev().find(|&index| self.is_used(index)) {
+                // skip past the used frame and realign
+                Some(used) => start = align_up((used + 1) as u64, align_frames as u64) as usize,
+                None => {
+                    for index in start..start + count {
+                        self.set_bit(index);
+                        self.ref_counts[index] = 1;
+                        self.account(index, -1);
+                    }
+                    self.free_frames -= count;
+                    return Some(Self::frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Drops one reference to each of the `count` contiguous frames starting
+    /// at `frame`. Frames whose last reference is dropped are freed.
+    ///
+    /// Panics if one of the frames is free or was not handed out by this
+    /// allocator: freeing it would put a frame the allocator does not own
+    /// into the pool, or count a free frame twice.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that it owns a reference to the frames and does
+    /// not use them afterwards.
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        let start = Self::index_of(frame);
+        // frames beyond the end of usable memory were never handed out
+        let end = start.saturating_add(count).min(self.frame_count);
+        for index in start..end {
+            assert!(
+                self.ref_counts[index] > 0,
+                "freeing frame {:#x}, which is free or was not allocated",
+                index as u64 * FRAME_SIZE
+            );
+            if self.ref_counts[index] > 1 {
+                self.ref_counts[index] -= 1;
+                continue;
+            }
+            self.ref_counts[index] = 0;
+            self.clear_bit(index);
+            self.free_frames += 1;
+            self.account(index, 1);
+        }
+        self.next_word = self.next_word.min(start / BITS_PER_WORD);
+    }
+
+    /// Adds a reference to an allocated frame, e.g. when it gets mapped into
+    /// another address space. Each reference must be dropped with a separate
+    /// call to `deallocate_frame`.
+    ///
+    /// Panics if the frame was not handed out by this allocator: its last
+    /// reference would put a frame the allocator does not own into the pool.
+    pub fn share(&mut self, frame: PhysFrame) {
+        let index = Self::index_of(frame);
+        assert!(
+            index < self.frame_count && self.ref_counts[index] > 0,
+            "sharing frame {:#x}, which was not allocated",
+            frame.start_address().as_u64()
+        );
+        self.ref_counts[index] = self.ref_counts[index]
+            .checked_add(1)
+            .expect("frame reference count overflow");
+    }
+
+    /// Returns the number of references to `frame`, `0` if it is free or was
+    /// not handed out by this allocator.
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        self.ref_counts[Self::index_of(frame)] as usize
+    }
+
+    /// Marks a range of frames as used without handing them out.
+    fn reserve(&mut self, start: usize, count: usize) {
+        for index in start..(start + count).min(self.frame_count) {
+            if !self.is_used(index) {
+                self.set_bit(index);
+                self.free_frames -= 1;
+            }
+        }
+    }
+
+    fn allocate_single(&mut self) -> Option<PhysFrame> {
+        let word_count = self.bitmap.len();
+        for offset in 0..word_count {
+            let word_index = (self.next_word + offset) % word_count;
+            let word = self.bitmap[word_index];
+            if word == u64::MAX {
+                continue;
+            }
+            let index = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+            if index >= self.frame_count {
+                continue;
+            }
+            self.set_bit(index);
+            self.ref_counts[index] = 1;
+            self.free_frames -= 1;
+            self.account(index, -1);
+            self.next_word = word_index;
+            return Some(Self::frame_at(index));
+        }
+        None
+    }
+
+    fn index_of(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    /// Prefers frames on the NUMA node of the current processor.
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        match super::numa::current_node() {
+            Some(node) if !self.nodes.is_empty() => self.allocate_near(node),
+            _ => self.allocate_single(),
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let count = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+        self.allocate_contiguous(count, Size2MiB::SIZE)
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let count = (Size1GiB::SIZE / FRAME_SIZE) as usize;
+        self.allocate_contiguous(count, Size1GiB::SIZE)
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let first = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(first, (S::SIZE / FRAME_SIZE) as usize);
+    }
+}
+
+unsafe impl Send for BitmapFrameAllocator {}
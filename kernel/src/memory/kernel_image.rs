@@ -0,0 +1,96 @@
+use bootloader_api::BootInfo;
+use x86_64::{
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::vmm::KERNEL_VMM;
+use super::{MAPPER, PHYS_MEM_OFFSET};
+use crate::elf::{ElfFile, ProgramHeader};
+
+/// Enables no-execute support and remaps the kernel image so that no page is
+/// both writable and executable: code becomes read-only, and read-only and
+/// writable data become non-executable. The physical memory mapping is made
+/// non-executable as well.
+///
+/// The permissions come from the program headers of the kernel ELF file,
+/// which the bootloader leaves in memory.
+pub fn protect(boot_info: &'static BootInfo) {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+
+    let phys_mem_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+    let image = unsafe {
+        core::slice::from_raw_parts(
+            (phys_mem_offset + boot_info.kernel_addr).as_ptr::<u8>(),
+            boot_info.kernel_len as usize,
+        )
+    };
+    let elf = ElfFile::parse(image).expect("kernel image is not a valid ELF file");
+    let segments = || elf.program_headers().filter(ProgramHeader::is_load);
+
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut writable_and_executable = 0;
+    let mut last_counted = None;
+    for segment in segments() {
+        let start = VirtAddr::new(boot_info.kernel_image_offset + segment.vaddr);
+        let end = start + segment.mem_size;
+        if segment.mem_size == 0 {
+            continue;
+        }
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(end - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            // a page shared by two segments gets the permissions of both
+            let sharing = segments().filter(|other| {
+                let other_start = boot_info.kernel_image_offset + other.vaddr;
+                let page_start = page.start_address().as_u64();
+                other_start < page_start + Size4KiB::SIZE && page_start < other_start + other.mem_size
+            });
+            let (writable, executable) = sharing.fold((false, false), |(w, x), other| {
+                (w || other.is_writable(), x || other.is_executable())
+            });
+
+            // segments are sorted, so a shared page is visited twice in a row
+            if writable && executable && last_counted != Some(page) {
+                writable_and_executable += 1;
+                last_counted = Some(page);
+            }
+            let mut flags = PageTableFlags::PRESENT;
+            if writable {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !executable {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.flush();
+            }
+        }
+    }
+
+    // the physical memory mapping has level 4 entries of its own, so marking
+    // those entries is enough to cover the whole mapping
+    let physical_memory = *KERNEL_VMM
+        .try_get()
+        .unwrap()
+        .lock()
+        .find(phys_mem_offset)
+        .expect("physical memory mapping is not registered");
+    let first = usize::from(physical_memory.start.p4_index());
+    let last = usize::from((physical_memory.end() - 1u64).p4_index());
+    let level_4_table = mapper.level_4_table();
+    for entry in level_4_table.iter_mut().take(last + 1).skip(first) {
+        entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+    }
+    drop(mapper);
+    x86_64::instructions::tlb::flush_all();
+
+    // this runs before the heap exists, so the allocating logger is not an option
+    if writable_and_executable > 0 {
+        crate::emergency_println!(
+            "WARNING: {} kernel pages are shared by code and writable data and stay writable and executable",
+            writable_and_executable
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use x86_64::{
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::vmm::{self, VmaKind};
+use super::{MapError, FRAME_ALLOCATOR, MAPPER};
+
+/// Caching behaviour of an MMIO mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal cached memory, only for device memory that tolerates it.
+    WriteBack,
+    /// Reads are cached, writes go straight to the device.
+    WriteThrough,
+    /// Every access goes to the device. Use this for registers.
+    Uncached,
+}
+
+impl CacheMode {
+    pub(super) fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A type an MMIO register can be read as. Only the unsigned integers are
+/// allowed, since every bit pattern a device returns is a valid value of
+/// them and each access is a single load or store.
+pub trait Register: sealed::Sealed + Copy {}
+
+macro_rules! register {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl Register for $ty {}
+        )*
+    };
+}
+
+register!(u8, u16, u32, u64);
+
+/// An owned mapping of device memory. The pages are unmapped when the region
+/// is dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    /// Virtual address of `phys`, not necessarily page aligned.
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a pointer to the register at `offset` bytes into the region.
+    pub fn as_ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.len,
+            "MMIO access at {:#x} is out of bounds",
+            offset
+        );
+        (self.virt + offset).as_mut_ptr()
+    }
+
+    /// Reads the register at `offset` bytes into the region.
+    ///
+    /// Panics if the register is out of bounds or not naturally aligned.
+    pub fn read<T: Register>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_volatile(self.register_ptr(offset)) }
+    }
+
+    /// Writes the register at `offset` bytes into the region.
+    ///
+    /// Panics if the register is out of bounds or not naturally aligned.
+    pub fn write<T: Register>(&self, offset: usize, value: T) {
+        unsafe { core::ptr::write_volatile(self.register_ptr(offset), value) }
+    }
+
+    fn register_ptr<T: Register>(&self, offset: usize) -> *mut T {
+        let ptr = self.as_ptr::<T>(offset);
+        assert!(
+            ptr.is_aligned(),
+            "MMIO access at {:#x} is not aligned to {} bytes",
+            offset,
+            core::mem::align_of::<T>()
+        );
+        ptr
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::<Size4KiB>::containing_address(self.virt);
+        let end = Page::<Size4KiB>::containing_address(self.virt + self.len - 1u64);
+        Page::range_inclusive(start, end)
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let mut mapper = MAPPER.try_get().unwrap().lock();
+        for page in self.pages() {
+            // the frames belong to the device, so they are not deallocated
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+        drop(mapper);
+        vmm::free(self.virt.align_down(Size4KiB::SIZE));
+    }
+}
+
+/// Maps `len` bytes of device memory starting at `phys` with the given cache
+/// mode and returns an owning handle to it.
+pub fn map_mmio(phys: PhysAddr, len: usize, cache_mode: CacheMode) -> Result<MmioRegion, MapError> {
+    if len == 0 {
+        return Err(MapError::InvalidRange);
+    }
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (len - 1) as u64);
+    let page_count = last_frame - first_frame + 1;
+    let size = page_count * Size4KiB::SIZE;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache_mode.flags();
+    let virt_start = vmm::allocate(size, "mmio", VmaKind::Mmio, flags)?;
+    let page_offset = phys.as_u64() - first_frame.start_address().as_u64();
+    let region = MmioRegion {
+        virt: virt_start + page_offset,
+        phys,
+        len,
+    };
+
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+    for (page, frame) in region.pages().zip(PhysFrame::range_inclusive(first_frame, last_frame)) {
+        // the pages mapped so far are unmapped again when `region` is dropped
+        let flush = unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }?;
+        flush.flush();
+    }
+
+    Ok(region)
+}
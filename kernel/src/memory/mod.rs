@@ -0,0 +1,450 @@
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    structures::paging::PageTable,
+    VirtAddr,
+};
+
+use crate::sync::NoPreemptMutex;
+
+mod frame_allocator;
+pub use frame_allocator::{BitmapFrameAllocator, FrameStats, MemoryNode};
+mod mmio;
+pub use mmio::{map_mmio, CacheMode, MmioRegion};
+mod dma;
+pub use dma::{DmaBlock, DmaBuffer, DmaPool, DmaRegion};
+mod stack;
+pub use stack::KernelStack;
+mod stats;
+pub use stats::{memory_map, stats, MemoryStats};
+pub mod vmm;
+pub mod address_space;
+pub mod swap;
+pub mod numa;
+pub mod page_fault;
+mod kernel_image;
+
+pub static PHYS_MEM_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+pub static FRAME_ALLOCATOR: OnceCell<NoPreemptMutex<BitmapFrameAllocator>> = OnceCell::uninit();
+pub static MAPPER: OnceCell<NoPreemptMutex<OffsetPageTable>> = OnceCell::uninit();
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+use x86_64::PhysAddr;
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    translate_addr_inner(addr, physical_memory_offset)
+}
+
+/// Private function that is called by `translate_addr`.
+///
+/// This function is safe to limit the scope of `unsafe` because Rust treats
+/// the whole body of unsafe functions as an unsafe block. This function must
+/// only be reachable through `unsafe fn` from outside of this module.
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::registers::control::Cr3;
+
+    // read the active level 4 frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+
+    // traverse the multi-level page table
+    for (level, &index) in table_indexes.iter().enumerate() {
+        // convert the frame into a page table reference
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table_ptr: *const PageTable = virt.as_ptr();
+        let table = unsafe {&*table_ptr};
+
+        // read the page table entry and update `frame`
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => {
+                // a huge entry maps the rest of the address directly. Its
+                // bit 12 is the PAT bit, not part of the frame address.
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => return None,
+                };
+                let frame = PhysAddr::new(entry.addr().as_u64() & !(page_size - 1));
+                return Some(frame + (addr.as_u64() & (page_size - 1)));
+            }
+        };
+    }
+
+    // calculate the physical address by adding the page offset
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+}
+
+/// Maps `len` bytes of physical memory starting at `phys` to `virt` using
+/// pages of size `S`. Both addresses must be aligned to `S`. On failure, the
+/// pages mapped so far are unmapped again.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// physical range is not in use by anything else and that mapping it with
+/// `flags` does not violate memory safety.
+pub unsafe fn map_pages<S: PageSize>(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let start_page = Page::<S>::from_start_address(virt).expect("virtual address is not aligned");
+    let start_frame = PhysFrame::<S>::from_start_address(phys).expect("physical address is not aligned");
+    let count = (len + S::SIZE - 1) / S::SIZE;
+
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+    for i in 0..count {
+        let page = start_page + i;
+        let frame = start_frame + i;
+        match mapper.map_to(page, frame, flags, &mut *frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                // the frames belong to the caller, so they are not deallocated
+                for mapped in Page::range(start_page, page) {
+                    if let Ok((_, flush)) = mapper.unmap(mapped) {
+                        flush.flush();
+                    }
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Maps `len` bytes of physical memory starting at `phys` to `virt`, using
+/// 1 GiB and 2 MiB pages wherever both addresses are suitably aligned and
+/// 4 KiB pages for the rest. On failure, nothing stays mapped.
+///
+/// This function is unsafe for the same reasons as [`map_pages`].
+pub unsafe fn map_range(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut offset = 0;
+    while offset < len {
+        let size = range_page_size(virt + offset, phys + offset, len - offset);
+        let result = match size {
+            Size1GiB::SIZE => map_pages::<Size1GiB>(virt + offset, phys + offset, size, flags).map_err(huge_map_error),
+            Size2MiB::SIZE => map_pages::<Size2MiB>(virt + offset, phys + offset, size, flags).map_err(huge_map_error),
+            _ => map_pages::<Size4KiB>(virt + offset, phys + offset, size, flags),
+        };
+        if let Err(err) = result {
+            unmap_range(virt, phys, offset);
+            return Err(err);
+        }
+        offset += size;
+    }
+    Ok(())
+}
+
+/// Undoes the first `len` bytes of a [`map_range`] with the same arguments.
+unsafe fn unmap_range(virt: VirtAddr, phys: PhysAddr, len: u64) {
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut offset = 0;
+    while offset < len {
+        let size = range_page_size(virt + offset, phys + offset, len - offset);
+        let page = virt + offset;
+        // the frames belong to the caller, so they are not deallocated
+        let _ = match size {
+            Size1GiB::SIZE => mapper.unmap(Page::<Size1GiB>::containing_address(page)).map(|(_, flush)| flush.flush()),
+            Size2MiB::SIZE => mapper.unmap(Page::<Size2MiB>::containing_address(page)).map(|(_, flush)| flush.flush()),
+            _ => mapper.unmap(Page::<Size4KiB>::containing_address(page)).map(|(_, flush)| flush.flush()),
+        };
+        offset += size;
+    }
+}
+
+/// Returns the largest page size [`map_range`] uses at `virt`, with `left`
+/// bytes left to map.
+fn range_page_size(virt: VirtAddr, phys: PhysAddr, left: u64) -> u64 {
+    let fits = |size: u64| virt.is_aligned(size) && phys.is_aligned(size) && left >= size;
+    if fits(Size1GiB::SIZE) {
+        Size1GiB::SIZE
+    } else if fits(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Maps `len` bytes of physical memory starting at `phys` into a new kernel
+/// area named `name`, with huge pages where the range allows, and returns
+/// the virtual address of `phys`. The area is placed so that virtual and
+/// physical addresses line up within each 2 MiB page.
+///
+/// This function is unsafe for the same reasons as [`map_pages`].
+pub unsafe fn map_physical(
+    phys: PhysAddr,
+    len: u64,
+    name: &'static str,
+    kind: vmm::VmaKind,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, MapError> {
+    let first = phys.align_down(Size4KiB::SIZE);
+    let offset = first.as_u64() % Size2MiB::SIZE;
+    let size = (phys.as_u64() - first.as_u64()) + len;
+    let size = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE;
+    let start = vmm::allocate_aligned(offset + size, Size2MiB::SIZE, name, kind, flags)?;
+    if let Err(err) = map_range(start + offset, first, size, flags) {
+        vmm::free(start);
+        return Err(err.into());
+    }
+    Ok(start + offset + (phys - first))
+}
+
+/// Converts a mapping error of a huge page size into the 4 KiB equivalent.
+fn huge_map_error<S: PageSize>(err: MapToError<S>) -> MapToError<Size4KiB> {
+    match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        ),
+    }
+}
+
+use x86_64::structures::paging::OffsetPageTable;
+
+/// Initialize a new OffsetPageTable.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+
+/*pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYS_MEM_OFFSET.init_once(|| {
+        physical_memory_offset.clone()
+    });
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}*/
+
+pub fn init(boot_info: &'static BootInfo) {
+    let offset = boot_info.physical_memory_offset.clone();
+    let phys_mem_offset = VirtAddr::new(offset.into_option().unwrap());
+    unsafe {
+        let frame_allocator = BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset);
+        FRAME_ALLOCATOR.init_once(|| NoPreemptMutex::new(frame_allocator));
+        stats::init(&boot_info.memory_regions);
+        PHYS_MEM_OFFSET.init_once(|| phys_mem_offset);
+
+        let page_table = active_level_4_table(phys_mem_offset);
+        vmm::init(boot_info, page_table, phys_mem_offset);
+        address_space::init(page_table);
+        let mapper = OffsetPageTable::new(page_table, phys_mem_offset);
+        MAPPER.init_once(|| NoPreemptMutex::new(mapper));
+    }
+    kernel_image::protect(boot_info);
+}
+
+/// Fills `frame` with zeroes through the physical memory mapping.
+pub(crate) fn zero_frame(frame: PhysFrame) {
+    let virt = *PHYS_MEM_OFFSET.try_get().unwrap() + frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize) };
+}
+
+/// Sets the cache mode of the physical memory mapping of `count` frames
+/// starting at `first`.
+///
+/// Memory that is mapped with different memory types at two addresses may
+/// be corrupted by the cache, so anything that maps frames with a cache mode
+/// other than [`CacheMode::WriteBack`] changes their physical memory mapping
+/// to match while it uses them, and changes it back before freeing them. The
+/// huge pages the bootloader mapped physical memory with are split into 4 KiB
+/// pages for this; they stay split afterwards.
+///
+/// This function is unsafe because nothing may access the frames through the
+/// physical memory mapping with the old cache mode while it runs.
+pub(crate) unsafe fn set_phys_cache_mode(
+    first: PhysFrame,
+    count: u64,
+    cache_mode: CacheMode,
+) -> Result<(), MapError> {
+    use x86_64::instructions::tlb;
+    use x86_64::structures::paging::page_table::PageTableEntry;
+
+    /// Replaces the huge page mapped by `entry` with a table of the next
+    /// level that maps the same memory with the same flags.
+    unsafe fn split(
+        entry: &mut PageTableEntry,
+        child_size: u64,
+        phys_offset: VirtAddr,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), MapError> {
+        let flags = entry.flags();
+        // bit 12 of a huge page entry is its PAT bit, not part of the address
+        let pat = entry.addr().as_u64() & Size4KiB::SIZE != 0;
+        let base = entry.addr().as_u64() & !(child_size * 512 - 1);
+
+        let frame = frame_allocator.allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+        zero_frame(frame);
+        let table = &mut *(phys_offset + frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+        for (i, child) in table.iter_mut().enumerate() {
+            let addr = base + i as u64 * child_size;
+            if child_size == Size4KiB::SIZE {
+                // the PAT bit of a 4 KiB entry is where the huge page bit is
+                let mut child_flags = flags - PageTableFlags::HUGE_PAGE;
+                child_flags.set(PageTableFlags::HUGE_PAGE, pat);
+                child.set_addr(PhysAddr::new(addr), child_flags);
+            } else {
+                let pat_bit = if pat { Size4KiB::SIZE } else { 0 };
+                child.set_addr(PhysAddr::new(addr | pat_bit), flags);
+            }
+        }
+
+        let table_flags = flags
+            & (PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE
+                | PageTableFlags::NO_EXECUTE);
+        entry.set_addr(frame.start_address(), table_flags);
+        tlb::flush_all();
+        Ok(())
+    }
+
+    let phys_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+    let mut mapper = MAPPER.try_get().unwrap().lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+    for frame in PhysFrame::range(first, first + count) {
+        let addr = phys_offset + frame.start_address().as_u64();
+        let mut table = &mut *(mapper.level_4_table() as *mut PageTable);
+        for (index, child_size) in [
+            (addr.p4_index(), None),
+            (addr.p3_index(), Some(Size1GiB::SIZE)),
+            (addr.p2_index(), Some(Size2MiB::SIZE)),
+        ] {
+            let entry = &mut table[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return Err(MapError::NotMapped);
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                let child_size = child_size.ok_or(MapError::ParentEntryHugePage)? / 512;
+                split(entry, child_size, phys_offset, &mut frame_allocator)?;
+            }
+            table = &mut *(phys_offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>();
+        }
+
+        let entry = &mut table[addr.p1_index()];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Err(MapError::NotMapped);
+        }
+        let flags = entry.flags()
+            - PageTableFlags::NO_CACHE
+            - PageTableFlags::WRITE_THROUGH
+            - PageTableFlags::HUGE_PAGE;
+        entry.set_flags(flags | cache_mode.flags());
+        tlb::flush(addr);
+    }
+    drop(frame_allocator);
+    drop(mapper);
+
+    // lines cached under the old memory type must not be written back later
+    core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    Ok(())
+}
+
+/// Errors returned by the mapping functions of this module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// No physical frame was left for the mapping or its page tables.
+    FrameAllocationFailed,
+    /// A page of the range is already mapped.
+    AlreadyMapped,
+    /// A page of the range lies inside an existing huge page.
+    ParentEntryHugePage,
+    /// The requested range is empty or not representable.
+    InvalidRange,
+    /// The requested range overlaps an area that is already in use.
+    Overlap,
+    /// The page is not mapped.
+    NotMapped,
+    /// The virtual window for this kind of mapping is used up.
+    OutOfVirtualSpace,
+    /// A swapped out page could not be read back.
+    SwapFailed,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MapError {
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MapError::FrameAllocationFailed,
+            MapToError::PageAlreadyMapped(_) => MapError::AlreadyMapped,
+            MapToError::ParentEntryHugePage => MapError::ParentEntryHugePage,
+        }
+    }
+}
+
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+    Size1GiB, Size2MiB, Size4KiB,
+};
+
+/// Creates an example mapping for the given page to frame `0xb8000`.
+pub fn create_example_mapping(
+    page: Page,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+
+    let map_to_result = unsafe {
+        // FIXME: this is not safe, we do it only for testing
+        mapper.map_to(page, frame, flags, frame_allocator)
+    };
+    map_to_result.expect("map_to failed").flush();
+}
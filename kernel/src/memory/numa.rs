@@ -0,0 +1,120 @@
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use conquer_once::spin::OnceCell;
+
+use super::{MemoryNode, FRAME_ALLOCATOR};
+use crate::acpi::{NumaInfo, ProcessorAffinity};
+
+/// Distances the ACPI specification uses when there is no SLIT.
+const LOCAL_DISTANCE: u8 = 10;
+const REMOTE_DISTANCE: u8 = 20;
+
+/// Marks [`CURRENT_NODE`] as unknown.
+const NO_NODE: u32 = u32::MAX;
+
+static TOPOLOGY: OnceCell<NumaTopology> = OnceCell::uninit();
+/// The node of the processor the kernel runs on, looked up once: `cpuid`
+/// traps to the hypervisor in a VM, which is too slow for every frame
+/// allocation. Only the boot processor runs for now, so one value is enough.
+static CURRENT_NODE: AtomicU32 = AtomicU32::new(NO_NODE);
+
+/// The NUMA nodes of the machine, the processors on them and how far apart
+/// they are.
+pub struct NumaTopology {
+    /// Node IDs in ascending order.
+    nodes: Vec<u32>,
+    processors: Vec<ProcessorAffinity>,
+    locality_count: usize,
+    distances: Vec<u8>,
+    /// For each entry of `nodes`, all nodes ordered by distance from it.
+    fallback_order: Vec<Vec<u32>>,
+}
+
+impl NumaTopology {
+    fn new(info: &NumaInfo) -> Self {
+        let mut nodes: Vec<u32> = info
+            .memory
+            .iter()
+            .map(|m| m.node)
+            .chain(info.processors.iter().map(|p| p.node))
+            .collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+
+        let mut topology = NumaTopology {
+            nodes,
+            processors: info.processors.clone(),
+            locality_count: info.locality_count,
+            distances: info.distances.clone(),
+            fallback_order: Vec::new(),
+        };
+        topology.fallback_order = topology
+            .nodes
+            .iter()
+            .map(|&from| {
+                let mut order = topology.nodes.clone();
+                // stable, so nodes at the same distance stay in ID order
+                order.sort_by_key(|&to| topology.distance(from, to));
+                order
+            })
+            .collect();
+        topology
+    }
+
+    pub fn nodes(&self) -> &[u32] {
+        &self.nodes
+    }
+
+    /// Returns the relative distance between two nodes, where 10 means local.
+    pub fn distance(&self, from: u32, to: u32) -> u8 {
+        let (from, to) = (from as usize, to as usize);
+        if from < self.locality_count && to < self.locality_count {
+            self.distances[from * self.locality_count + to]
+        } else if from == to {
+            LOCAL_DISTANCE
+        } else {
+            REMOTE_DISTANCE
+        }
+    }
+
+    /// Returns the node of the processor with the given APIC ID.
+    pub fn node_of_apic(&self, apic_id: u32) -> Option<u32> {
+        self.processors.iter().find(|p| p.apic_id == apic_id).map(|p| p.node)
+    }
+
+    /// Returns all nodes, closest to `node` first.
+    pub fn fallback_order(&self, node: u32) -> &[u32] {
+        self.nodes
+            .iter()
+            .position(|&n| n == node)
+            .map_or(&self.nodes[..], |index| &self.fallback_order[index])
+    }
+}
+
+/// Records the NUMA layout and assigns the frame allocator's frames to nodes.
+pub fn init(info: NumaInfo) {
+    TOPOLOGY.init_once(|| NumaTopology::new(&info));
+    let topology = TOPOLOGY.try_get().unwrap();
+    // the initial APIC ID, which is what the SRAT refers to for xAPIC IDs
+    let apic_id = unsafe { __cpuid(1) }.ebx >> 24;
+    let node = topology.node_of_apic(apic_id).unwrap_or(NO_NODE);
+    CURRENT_NODE.store(node, Ordering::Relaxed);
+    let nodes = MemoryNode::from_ranges(info.memory.iter().map(|m| (m.node, m.base, m.length)));
+    FRAME_ALLOCATOR.try_get().unwrap().lock().set_nodes(nodes);
+    log::info!("NUMA: {} nodes", topology.nodes.len());
+}
+
+/// Returns the NUMA layout, or `None` if the machine does not describe one.
+pub fn topology() -> Option<&'static NumaTopology> {
+    TOPOLOGY.try_get().ok()
+}
+
+/// Returns the node of the processor this runs on.
+pub fn current_node() -> Option<u32> {
+    match CURRENT_NODE.load(Ordering::Relaxed) {
+        NO_NODE => None,
+        node => Some(node),
+    }
+}
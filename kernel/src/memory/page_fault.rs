@@ -0,0 +1,94 @@
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use super::address_space::{self, USER_END};
+use super::stack::GUARD_SIZE;
+use super::vmm::{Backing, VmaKind, KERNEL_VMM};
+use super::{zero_frame, FRAME_ALLOCATOR, MAPPER};
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The page is present but the access is not allowed by its flags.
+    ProtectionViolation,
+    /// The address does not belong to any lazily backed region.
+    NoRegion,
+    /// The region does not allow this kind of access.
+    AccessViolation,
+    /// No frame was left to back the page.
+    OutOfMemory,
+    /// A lock the handler needs is held by the interrupted code.
+    Locked,
+    /// A swapped out page could not be read back.
+    SwapFailed,
+    /// The access hit the guard page of the named kernel stack.
+    StackOverflow(&'static str),
+}
+
+/// Tries to resolve a page fault at `addr`, either by mapping a zeroed frame
+/// into a lazily backed region or by copying a copy-on-write page.
+///
+/// This runs in the page fault handler, so it must not allocate on the heap
+/// or log, and it only ever tries the locks it needs: if the faulting code
+/// holds one of them, the fault is reported as [`FaultError::Locked`]
+/// instead of deadlocking.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // the only protection faults that can be resolved are writes to
+        // copy-on-write pages
+        if write && addr.as_u64() < USER_END {
+            return address_space::handle_cow_fault(addr);
+        }
+        return Err(FaultError::ProtectionViolation);
+    }
+
+    if addr.as_u64() < USER_END {
+        return address_space::handle_user_fault(addr, write);
+    }
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        return Err(FaultError::AccessViolation);
+    }
+    handle_kernel_fault(addr, write)
+}
+
+/// Maps a zeroed frame for a fault inside a [`Backing::ZeroFill`] kernel area.
+fn handle_kernel_fault(addr: VirtAddr, write: bool) -> Result<(), FaultError> {
+    let vmm = KERNEL_VMM.try_get().map_err(|_| FaultError::NoRegion)?;
+    let flags = {
+        let vmm = vmm.try_lock().ok_or(FaultError::Locked)?;
+        let vma = vmm.find(addr).ok_or(FaultError::NoRegion)?;
+        if vma.kind == VmaKind::Stack && addr < vma.start + GUARD_SIZE {
+            return Err(FaultError::StackOverflow(vma.name));
+        }
+        if vma.backing != Backing::ZeroFill {
+            return Err(FaultError::NoRegion);
+        }
+        vma.flags
+    };
+    if write && !flags.contains(PageTableFlags::WRITABLE) {
+        return Err(FaultError::AccessViolation);
+    }
+
+    let mut mapper = MAPPER.try_get().unwrap().try_lock().ok_or(FaultError::Locked)?;
+    let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().try_lock().ok_or(FaultError::Locked)?;
+    let frame = frame_allocator.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+    zero_frame(frame);
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(FaultError::OutOfMemory)
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::vmm::{self, VmaKind};
+use super::{MapError, FRAME_ALLOCATOR, MAPPER};
+
+/// Size of the unmapped guard area below every kernel stack.
+pub const GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+const STACK_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// A kernel stack with an unmapped guard page below it.
+///
+/// The stack and its guard page form a single [`VmaKind::Stack`] area, so the
+/// page fault handler can tell an overflow apart from other faults and name
+/// the stack that overflowed. The pages are mapped up front, since a stack
+/// must never fault while it is in use by a fault handler, except for the
+/// lower part of thread stacks (see [`KernelStack::new_lazy`]).
+#[derive(Debug)]
+pub struct KernelStack {
+    /// Start of the area, which is the guard page.
+    start: VirtAddr,
+    /// Size of the usable stack, without the guard page.
+    size: u64,
+}
+
+impl KernelStack {
+    /// Allocates a stack of at least `size` bytes named `name`.
+    pub fn new(size: u64, name: &'static str) -> Result<Self, MapError> {
+        let size = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE;
+        if size == 0 {
+            return Err(MapError::InvalidRange);
+        }
+        let start = vmm::allocate(size + GUARD_SIZE, name, VmaKind::Stack, STACK_FLAGS)?;
+        let stack = KernelStack { start, size };
+        stack.map(stack.pages())?;
+        Ok(stack)
+    }
+
+    /// Allocates a stack of at least `size` bytes named `name` of which only
+    /// the top `resident` bytes are mapped up front. The rest is mapped to
+    /// zeroed frames by the page fault handler as the stack grows into it.
+    ///
+    /// Only for thread stacks: a fault on the lazy part finds the frame
+    /// allocator locked if the thread holds it itself, so `resident` has to
+    /// cover what the thread uses while it holds the memory locks.
+    pub fn new_lazy(size: u64, resident: u64, name: &'static str) -> Result<Self, MapError> {
+        let size = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE;
+        if size == 0 {
+            return Err(MapError::InvalidRange);
+        }
+        let start = vmm::allocate_lazy(size + GUARD_SIZE, name, VmaKind::Stack, STACK_FLAGS)?;
+        let stack = KernelStack { start, size };
+        let resident = (resident.min(size) + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        stack.map(stack.pages().skip((size / Size4KiB::SIZE - resident) as usize))?;
+        Ok(stack)
+    }
+
+    /// Maps fresh frames for `pages`.
+    fn map(&self, pages: impl Iterator<Item = Page>) -> Result<(), MapError> {
+        let mut mapper = MAPPER.try_get().unwrap().lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        for page in pages {
+            // the pages mapped so far are released again when the stack is dropped
+            let frame = frame_allocator.allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+            let result = unsafe { mapper.map_to(page, frame, STACK_FLAGS, &mut *frame_allocator) };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the initial stack pointer, i.e. the end of the stack.
+    pub fn top(&self) -> VirtAddr {
+        self.start + GUARD_SIZE + self.size
+    }
+
+    /// Returns the lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.start + GUARD_SIZE
+    }
+
+    /// Keeps the stack mapped forever and returns its top. Used for stacks
+    /// that live as long as the kernel, like the IST stacks.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::<Size4KiB>::containing_address(self.bottom());
+        let last = Page::<Size4KiB>::containing_address(self.top() - 1u64);
+        Page::range_inclusive(first, last)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut mapper = MAPPER.try_get().unwrap().lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.try_get().unwrap().lock();
+        for page in self.pages() {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+        drop(frame_allocator);
+        drop(mapper);
+        vmm::free(self.start);
+    }
+}
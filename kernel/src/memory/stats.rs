@@ -0,0 +1,69 @@
+use bootloader_api::info::{MemoryRegion, MemoryRegions};
+use conquer_once::spin::OnceCell;
+use x86_64::structures::paging::{PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+
+use super::{FRAME_ALLOCATOR, MAPPER, PHYS_MEM_OFFSET};
+use crate::allocator::{self, HeapUsage};
+
+static MEMORY_MAP: OnceCell<&'static [MemoryRegion]> = OnceCell::uninit();
+
+pub(super) fn init(memory_regions: &'static MemoryRegions) {
+    let memory_regions: &'static [MemoryRegion] = memory_regions;
+    MEMORY_MAP.init_once(|| memory_regions);
+}
+
+/// Returns the memory map the bootloader handed to the kernel.
+pub fn memory_map() -> &'static [MemoryRegion] {
+    MEMORY_MAP.try_get().unwrap()
+}
+
+/// A snapshot of the kernel's physical and heap memory usage.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// Bytes covered by the bootloader memory map, of any kind.
+    pub total_bytes: u64,
+    /// Bytes the bootloader reported as usable.
+    pub usable_bytes: u64,
+    /// Usable bytes that are currently allocated.
+    pub used_bytes: u64,
+    /// Frames holding the kernel's own page tables.
+    pub page_table_frames: usize,
+    pub heap: HeapUsage,
+}
+
+/// Collects the current memory usage.
+pub fn stats() -> MemoryStats {
+    let total_bytes = memory_map().iter().map(|r| r.end - r.start).sum();
+    let frames = FRAME_ALLOCATOR.try_get().unwrap().lock().stats();
+
+    let page_table_frames = {
+        let mut mapper = MAPPER.try_get().unwrap().lock();
+        unsafe { count_tables(mapper.level_4_table(), 4) }
+    };
+
+    MemoryStats {
+        total_bytes,
+        usable_bytes: frames.total_frames as u64 * Size4KiB::SIZE,
+        used_bytes: frames.used_frames() as u64 * Size4KiB::SIZE,
+        page_table_frames,
+        heap: allocator::heap_usage(),
+    }
+}
+
+/// Counts `table` and every page table below it.
+unsafe fn count_tables(table: &PageTable, level: u8) -> usize {
+    if level == 1 {
+        return 1;
+    }
+    let phys_mem_offset = *PHYS_MEM_OFFSET.try_get().unwrap();
+    let children = table
+        .iter()
+        .filter(|entry| !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE))
+        .map(|entry| {
+            let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+            let child = &*(phys_mem_offset + frame.start_address().as_u64()).as_ptr::<PageTable>();
+            count_tables(child, level - 1)
+        })
+        .sum::<usize>();
+    1 + children
+}
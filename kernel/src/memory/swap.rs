@@ -0,0 +1,271 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    structures::paging::{page_table::PageTableEntry, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::address_space::{self, AddressSpace};
+use super::{MapError, FRAME_ALLOCATOR, PHYS_MEM_OFFSET};
+use crate::sync::{NoPreemptMutex, NoPreemptMutexGuard};
+
+/// Software-defined page table bit that marks a non-present entry whose page
+/// was written to swap. The address bits of such an entry hold the slot.
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+static SWAP: OnceCell<NoPreemptMutex<Swap>> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    /// No swap device was registered.
+    Disabled,
+    /// Every slot of the swap device is in use.
+    Full,
+    /// The device failed to read or write a slot.
+    Io,
+}
+
+/// Storage for pages that were evicted from memory, e.g. a swap partition
+/// or a swap file on a block device. Slots are one page large.
+///
+/// Slots are read and written from the page fault handler, with interrupts
+/// disabled. An implementation must finish the transfer by polling, without
+/// sleeping or waiting for an interrupt, and must not take locks that code
+/// running with interrupts enabled may hold.
+pub trait SwapDevice: Send {
+    fn slot_count(&self) -> usize;
+
+    fn read_slot(&mut self, slot: usize, buf: &mut [u8; PAGE_SIZE]) -> Result<(), SwapError>;
+
+    fn write_slot(&mut self, slot: usize, buf: &[u8; PAGE_SIZE]) -> Result<(), SwapError>;
+}
+
+/// The registered swap device and the slots in use on it.
+pub struct Swap {
+    device: Box<dyn SwapDevice>,
+    /// One bit per slot, set if the slot holds a page.
+    used: Vec<u64>,
+    free_slots: usize,
+}
+
+/// Counters of the swap device.
+#[derive(Debug, Clone, Copy)]
+pub struct SwapStats {
+    pub total_slots: usize,
+    pub free_slots: usize,
+}
+
+impl Swap {
+    /// Writes the contents of `frame` to a free slot and returns the slot.
+    pub fn write_frame(&mut self, frame: PhysFrame) -> Result<usize, SwapError> {
+        let slot = self.allocate_slot().ok_or(SwapError::Full)?;
+        if let Err(err) = self.device.write_slot(slot, frame_bytes(frame)) {
+            self.free(slot);
+            return Err(err);
+        }
+        Ok(slot)
+    }
+
+    /// Reads the page stored in `slot` into `frame`. The slot stays in use.
+    pub fn read_frame(&mut self, slot: usize, frame: PhysFrame) -> Result<(), SwapError> {
+        self.device.read_slot(slot, frame_bytes(frame))
+    }
+
+    /// Releases `slot`.
+    pub fn free(&mut self, slot: usize) {
+        let bit = 1 << (slot % BITS_PER_WORD);
+        debug_assert!(self.used[slot / BITS_PER_WORD] & bit != 0, "double free of swap slot {}", slot);
+        self.used[slot / BITS_PER_WORD] &= !bit;
+        self.free_slots += 1;
+    }
+
+    pub fn stats(&self) -> SwapStats {
+        SwapStats {
+            total_slots: self.device.slot_count(),
+            free_slots: self.free_slots,
+        }
+    }
+
+    fn allocate_slot(&mut self) -> Option<usize> {
+        let slot_count = self.device.slot_count();
+        let (word_index, word) = self.used.iter_mut().enumerate().find(|(_, word)| **word != u64::MAX)?;
+        let slot = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+        if slot >= slot_count {
+            return None;
+        }
+        *word |= 1 << (slot % BITS_PER_WORD);
+        self.free_slots -= 1;
+        Some(slot)
+    }
+}
+
+/// A swap device in kernel memory. It frees nothing overall, but exercises
+/// eviction and page-in without a block device, e.g. on a guest with
+/// `-m 32M` and [`pressure_test`].
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// Creates a RAM disk with room for `size` bytes of pages.
+    pub fn new(size: usize) -> Self {
+        RamDisk {
+            data: vec![0; size / PAGE_SIZE * PAGE_SIZE],
+        }
+    }
+
+    fn slot(&mut self, slot: usize) -> Result<&mut [u8], SwapError> {
+        self.data.get_mut(slot * PAGE_SIZE..(slot + 1) * PAGE_SIZE).ok_or(SwapError::Io)
+    }
+}
+
+impl SwapDevice for RamDisk {
+    fn slot_count(&self) -> usize {
+        self.data.len() / PAGE_SIZE
+    }
+
+    fn read_slot(&mut self, slot: usize, buf: &mut [u8; PAGE_SIZE]) -> Result<(), SwapError> {
+        buf.copy_from_slice(self.slot(slot)?);
+        Ok(())
+    }
+
+    fn write_slot(&mut self, slot: usize, buf: &[u8; PAGE_SIZE]) -> Result<(), SwapError> {
+        self.slot(slot)?.copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+fn frame_bytes(frame: PhysFrame) -> &'static mut [u8; PAGE_SIZE] {
+    let virt = *PHYS_MEM_OFFSET.try_get().unwrap() + frame.start_address().as_u64();
+    unsafe { &mut *virt.as_mut_ptr() }
+}
+
+/// Registers the device that anonymous user pages are paged out to. Only
+/// one swap device is supported, later ones are dropped.
+pub fn enable(device: Box<dyn SwapDevice>) {
+    let slot_count = device.slot_count();
+    let words = (slot_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+    SWAP.init_once(|| {
+        NoPreemptMutex::new(Swap {
+            device,
+            used: vec![0; words],
+            free_slots: slot_count,
+        })
+    });
+}
+
+/// Locks the swap state, or returns `None` if no device was registered.
+pub fn lock() -> Option<NoPreemptMutexGuard<'static, Swap>> {
+    SWAP.try_get().ok().map(|swap| swap.lock())
+}
+
+/// Like [`lock`], but also returns `None` instead of spinning if the lock is
+/// held. Used in the page fault handler.
+pub(super) fn try_lock() -> Option<NoPreemptMutexGuard<'static, Swap>> {
+    SWAP.try_get().ok()?.try_lock()
+}
+
+pub fn stats() -> Option<SwapStats> {
+    lock().map(|swap| swap.stats())
+}
+
+/// Returns the slot a swapped-out entry points to.
+pub fn swapped_slot(entry: &PageTableEntry) -> Option<usize> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) && flags.contains(SWAPPED) {
+        Some((entry.addr().as_u64() / Size4KiB::SIZE) as usize)
+    } else {
+        None
+    }
+}
+
+/// Turns a present entry into a swapped-out entry for `slot`, keeping its
+/// flags for when the page is read back.
+pub fn set_swapped(entry: &mut PageTableEntry, slot: usize) {
+    let flags = entry.flags() - PageTableFlags::PRESENT - PageTableFlags::ACCESSED - PageTableFlags::DIRTY;
+    entry.set_addr(PhysAddr::new(slot as u64 * Size4KiB::SIZE), flags | SWAPPED);
+}
+
+/// Turns a swapped-out entry back into a present entry mapping `frame`.
+pub fn set_swapped_in(entry: &mut PageTableEntry, frame: PhysFrame) {
+    let flags = (entry.flags() - SWAPPED) | PageTableFlags::PRESENT;
+    entry.set_frame(frame, flags);
+}
+
+/// Where [`pressure_test`] maps its memory.
+const PRESSURE_TEST_START: u64 = 0x0000_0000_4000_0000;
+/// Free frames below which [`pressure_test`] pages out ahead of its faults,
+/// so that the rest of the kernel can still allocate while it runs.
+const LOW_WATER_FRAMES: usize = 512;
+/// Pages [`pressure_test`] writes out at a time.
+const PAGE_OUT_BATCH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureTestError {
+    Swap(SwapError),
+    Map(MapError),
+    /// The memory does not fit into free frames and free swap slots.
+    TooLarge,
+    /// A page read back with different contents than were written to it.
+    Corrupted { addr: u64 },
+}
+
+/// Fills `size` bytes of anonymous memory in a scratch address space with a
+/// pattern and checks it afterwards. Whatever does not fit into memory goes
+/// through swap on the way, so this tests eviction and page-in under memory
+/// pressure. Returns the number of pages it wrote to swap itself, on top of
+/// those the page fault handler evicted.
+pub fn pressure_test(size: u64) -> Result<usize, PressureTestError> {
+    let pages = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let free_slots = stats().ok_or(PressureTestError::Swap(SwapError::Disabled))?.free_slots;
+    let free_frames = FRAME_ALLOCATOR.try_get().unwrap().lock().stats().free_frames;
+    // leave room for the page tables and whatever else runs meanwhile
+    if pages as usize > (free_frames + free_slots).saturating_sub(2 * LOW_WATER_FRAMES) {
+        return Err(PressureTestError::TooLarge);
+    }
+
+    let mut space = AddressSpace::new().map_err(PressureTestError::Map)?;
+    let start = VirtAddr::new(PRESSURE_TEST_START);
+    space
+        .map_anonymous(start, pages * Size4KiB::SIZE, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        .map_err(PressureTestError::Map)?;
+
+    // the scratch space is only active on this thread, and `space` outlives it
+    unsafe { space.activate() };
+    let result = fill_and_check(&mut space, start, pages);
+    unsafe { address_space::activate_kernel() };
+    result
+}
+
+fn fill_and_check(space: &mut AddressSpace, start: VirtAddr, pages: u64) -> Result<usize, PressureTestError> {
+    let mut swapped = 0;
+    let pattern = |page: u64| page.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    for page in 0..pages {
+        if FRAME_ALLOCATOR.try_get().unwrap().lock().stats().free_frames < LOW_WATER_FRAMES {
+            swapped += space.page_out(PAGE_OUT_BATCH).map_err(PressureTestError::Swap)?;
+        }
+        let ptr = (start + page * Size4KiB::SIZE).as_mut_ptr::<u64>();
+        unsafe {
+            ptr.write_volatile(pattern(page));
+            ptr.add(PAGE_SIZE / 8 - 1).write_volatile(!pattern(page));
+        }
+    }
+    for page in 0..pages {
+        if FRAME_ALLOCATOR.try_get().unwrap().lock().stats().free_frames < LOW_WATER_FRAMES {
+            swapped += space.page_out(PAGE_OUT_BATCH).map_err(PressureTestError::Swap)?;
+        }
+        let addr = start + page * Size4KiB::SIZE;
+        let ptr = addr.as_ptr::<u64>();
+        let (first, last) = unsafe { (ptr.read_volatile(), ptr.add(PAGE_SIZE / 8 - 1).read_volatile()) };
+        if first != pattern(page) || last != !pattern(page) {
+            return Err(PressureTestError::Corrupted { addr: addr.as_u64() });
+        }
+    }
+    Ok(swapped)
+}
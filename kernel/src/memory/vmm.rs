@@ -0,0 +1,277 @@
+use core::fmt;
+
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+use x86_64::{
+    structures::paging::{PageSize, PageTable, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::MapError;
+use crate::sync::NoPreemptMutex;
+
+/// Start of the part of the higher half that the kernel hands out itself.
+/// The bootloader is told to keep its dynamic mappings below this address.
+pub const KERNEL_VMA_START: u64 = 0xffff_c000_0000_0000;
+/// End (exclusive) of the range handed out by [`VirtualMemoryManager::allocate`].
+pub const KERNEL_VMA_END: u64 = 0xffff_ffff_ffff_f000;
+
+/// Size of the virtual range covered by one level 4 entry.
+const P4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024; // 512 GiB
+
+/// Maximum number of areas that can be tracked. The table is a plain array
+/// because it has to work before the heap exists.
+const MAX_AREAS: usize = 128;
+
+pub static KERNEL_VMM: OnceCell<NoPreemptMutex<VirtualMemoryManager>> = OnceCell::uninit();
+
+/// What a virtual memory area is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Set up by the bootloader (kernel image, boot info, physical memory map, ...).
+    Bootloader,
+    Heap,
+    Stack,
+    Mmio,
+    Dma,
+    PerCpu,
+    Module,
+}
+
+/// How the pages of an area get their frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// The owner of the area maps the pages itself.
+    Mapped,
+    /// Pages are mapped to zeroed frames by the page fault handler on first access.
+    ZeroFill,
+}
+
+/// A named range of kernel virtual addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub name: &'static str,
+    pub kind: VmaKind,
+    /// Flags that pages in this area are mapped with.
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start.as_u64() < end && start < self.end().as_u64()
+    }
+}
+
+impl fmt::Display for Vma {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{:#018x}-{:#018x} {}{}{}{} {:>10} KiB {:?} {}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            flag(self.flags.contains(PageTableFlags::PRESENT), 'r'),
+            flag(self.flags.contains(PageTableFlags::WRITABLE), 'w'),
+            flag(!self.flags.contains(PageTableFlags::NO_EXECUTE), 'x'),
+            flag(self.backing == Backing::ZeroFill, 'z'),
+            self.size / 1024,
+            self.kind,
+            self.name,
+        )
+    }
+}
+
+/// Tracks which kernel virtual ranges are in use and hands out new ones.
+pub struct VirtualMemoryManager {
+    /// Areas sorted by start address.
+    areas: [Option<Vma>; MAX_AREAS],
+    len: usize,
+}
+
+impl VirtualMemoryManager {
+    const fn new() -> Self {
+        VirtualMemoryManager {
+            areas: [None; MAX_AREAS],
+            len: 0,
+        }
+    }
+
+    /// Registers an area at a fixed address. Fails if it overlaps an existing area.
+    pub fn reserve(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        name: &'static str,
+        kind: VmaKind,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<(), MapError> {
+        let end = start.as_u64().checked_add(size).ok_or(MapError::InvalidRange)?;
+        if size == 0 {
+            return Err(MapError::InvalidRange);
+        }
+        if self.iter().any(|vma| vma.overlaps(start.as_u64(), end)) {
+            return Err(MapError::Overlap);
+        }
+        if self.len == MAX_AREAS {
+            return Err(MapError::OutOfVirtualSpace);
+        }
+
+        let index = self.iter().take_while(|vma| vma.start < start).count();
+        self.areas.copy_within(index..self.len, index + 1);
+        self.areas[index] = Some(Vma {
+            start,
+            size,
+            name,
+            kind,
+            flags,
+            backing,
+        });
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Finds a free range of `size` bytes aligned to `align` in the kernel's
+    /// part of the higher half and registers it.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        align: u64,
+        name: &'static str,
+        kind: VmaKind,
+        flags: PageTableFlags,
+        backing: Backing,
+    ) -> Result<VirtAddr, MapError> {
+        let size = align_up(size, Size4KiB::SIZE).ok_or(MapError::InvalidRange)?;
+        let align = align.max(Size4KiB::SIZE);
+
+        // first fit: try the window start and the end of every area after it
+        let candidates = core::iter::once(KERNEL_VMA_START)
+            .chain(self.iter().map(|vma| vma.end().as_u64()))
+            .filter(|&addr| addr >= KERNEL_VMA_START);
+        let start = candidates
+            .filter_map(|addr| align_up(addr, align))
+            .find(|&start| {
+                start
+                    .checked_add(size)
+                    .map_or(false, |end| end <= KERNEL_VMA_END && !self.iter().any(|vma| vma.overlaps(start, end)))
+            })
+            .ok_or(MapError::OutOfVirtualSpace)?;
+
+        let start = VirtAddr::new(start);
+        self.reserve(start, size, name, kind, flags, backing)?;
+        Ok(start)
+    }
+
+    /// Removes the area starting at `start` and returns it.
+    pub fn free(&mut self, start: VirtAddr) -> Option<Vma> {
+        let index = self.iter().position(|vma| vma.start == start)?;
+        let vma = self.areas[index].take();
+        self.areas.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.areas[self.len] = None;
+        vma
+    }
+
+    /// Returns the area containing `addr`, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.iter().find(|vma| vma.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas[..self.len].iter().flatten()
+    }
+}
+
+fn align_up(value: u64, align: u64) -> Option<u64> {
+    Some(value.checked_add(align - 1)? / align * align)
+}
+
+/// Sets up the virtual memory manager with the areas the bootloader created.
+///
+/// Known mappings get their own names; every other level 4 entry that is
+/// already present is reserved as a whole, since the bootloader gives each
+/// of its dynamic mappings separate level 4 entries.
+pub fn init(boot_info: &'static BootInfo, level_4_table: &PageTable, phys_mem_offset: VirtAddr) {
+    let mut vmm = VirtualMemoryManager::new();
+    let kernel_data = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let phys_mem_size = boot_info.memory_regions.iter().map(|r| r.end).max().unwrap_or(0);
+    let no_execute = kernel_data | PageTableFlags::NO_EXECUTE;
+    vmm.reserve(phys_mem_offset, phys_mem_size, "physical memory", VmaKind::Bootloader, no_execute, Backing::Mapped)
+        .expect("physical memory mapping overlaps another area");
+
+    if let Ok(fb) = crate::framebuffer::FBWRITER.try_get() {
+        let fb = fb.lock();
+        let start = VirtAddr::from_ptr(fb.framebuffer.as_ptr());
+        let size = fb.framebuffer.len() as u64;
+        vmm.reserve(start, size, "framebuffer", VmaKind::Bootloader, kernel_data, Backing::Mapped)
+            .expect("framebuffer mapping overlaps another area");
+    }
+
+    for (index, entry) in level_4_table.iter().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let start = VirtAddr::new_truncate(index as u64 * P4_ENTRY_SIZE);
+        // the area of the last entry would end at 2^64, so leave out its last page
+        let size = if index == 511 { P4_ENTRY_SIZE - Size4KiB::SIZE } else { P4_ENTRY_SIZE };
+        if vmm.iter().any(|vma| vma.overlaps(start.as_u64(), start.as_u64() + size)) {
+            continue;
+        }
+        vmm.reserve(start, size, "bootloader", VmaKind::Bootloader, entry.flags(), Backing::Mapped)
+            .expect("too many bootloader mappings");
+    }
+
+    KERNEL_VMM.init_once(|| NoPreemptMutex::new(vmm));
+}
+
+/// Allocates a range of kernel virtual addresses that the caller maps itself.
+/// See [`VirtualMemoryManager::allocate`].
+pub fn allocate(
+    size: u64,
+    name: &'static str,
+    kind: VmaKind,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, MapError> {
+    KERNEL_VMM.try_get().unwrap().lock().allocate(size, Size4KiB::SIZE, name, kind, flags, Backing::Mapped)
+}
+
+/// Allocates a range of kernel virtual addresses that starts at a multiple
+/// of `align`, for mappings that use huge pages.
+pub fn allocate_aligned(
+    size: u64,
+    align: u64,
+    name: &'static str,
+    kind: VmaKind,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, MapError> {
+    KERNEL_VMM.try_get().unwrap().lock().allocate(size, align, name, kind, flags, Backing::Mapped)
+}
+
+/// Allocates a range of kernel virtual addresses whose pages are mapped to
+/// zeroed frames on first access.
+pub fn allocate_lazy(
+    size: u64,
+    name: &'static str,
+    kind: VmaKind,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, MapError> {
+    KERNEL_VMM.try_get().unwrap().lock().allocate(size, Size4KiB::SIZE, name, kind, flags, Backing::ZeroFill)
+}
+
+/// Releases a range returned by [`allocate`]. The caller must have unmapped it.
+pub fn free(start: VirtAddr) -> Option<Vma> {
+    KERNEL_VMM.try_get().unwrap().lock().free(start)
+}
@@ -0,0 +1,347 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::cpu::user::{self, UserExit};
+use crate::loader::{self, LoadError};
+use crate::memory::address_space::{self, AddressSpace};
+use crate::thread::{self, JoinHandle, ThreadError};
+
+pub type Pid = u64;
+
+/// The pid of the kernel itself, which is the parent of processes it
+/// spawns and never appears in the process table.
+pub const KERNEL_PID: Pid = 0;
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+/// The process whose user code is running, or [`KERNEL_PID`]. Saved and
+/// restored with the thread that runs it.
+static CURRENT: AtomicU64 = AtomicU64::new(KERNEL_PID);
+static PROCESS_TABLE: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    /// Loaded, but its thread has not started yet.
+    Ready,
+    Running,
+    /// Finished and waiting for its parent to collect the exit status.
+    Exited(ExitStatus),
+}
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process called `exit` with this code.
+    Code(i32),
+    Killed,
+    /// The process raised an exception the kernel could not resolve.
+    Fault { vector: u64, rip: u64 },
+}
+
+impl From<UserExit> for ExitStatus {
+    fn from(exit: UserExit) -> Self {
+        match exit {
+            UserExit::Exited(code) => ExitStatus::Code(code),
+            UserExit::Killed => ExitStatus::Killed,
+            UserExit::Fault { vector, rip, .. } => ExitStatus::Fault { vector, rip },
+        }
+    }
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessState::Ready => write!(f, "ready"),
+            ProcessState::Running => write!(f, "running"),
+            ProcessState::Exited(ExitStatus::Code(code)) => write!(f, "exited({})", code),
+            ProcessState::Exited(ExitStatus::Killed) => write!(f, "killed"),
+            ProcessState::Exited(ExitStatus::Fault { vector, .. }) => write!(f, "faulted({})", vector),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    /// Only the parent of a process may wait for it.
+    NotChild,
+    /// Another thread is already waiting for the process.
+    AlreadyWaiting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Load(LoadError),
+    /// No thread could be started to run the process on.
+    Thread(ThreadError),
+}
+
+impl From<LoadError> for SpawnError {
+    fn from(err: LoadError) -> Self {
+        SpawnError::Load(err)
+    }
+}
+
+impl From<ThreadError> for SpawnError {
+    fn from(err: ThreadError) -> Self {
+        SpawnError::Thread(err)
+    }
+}
+
+/// An open file of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum File {
+    /// The framebuffer and serial console.
+    Console,
+}
+
+/// The open files of a process, indexed by file descriptor.
+#[derive(Debug, Clone)]
+pub struct FileTable {
+    files: Vec<Option<File>>,
+}
+
+impl FileTable {
+    /// Creates a table with standard input, output and error on the console.
+    pub fn with_console() -> Self {
+        FileTable {
+            files: alloc::vec![Some(File::Console); 3],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Option<File> {
+        self.files.get(usize::try_from(fd).ok()?).copied().flatten()
+    }
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: String,
+    pub state: ProcessState,
+    /// Dropped as soon as the process exits.
+    address_space: Option<AddressSpace>,
+    /// The kernel threads that run the user code of the process, handed to
+    /// whoever waits for it.
+    pub threads: Vec<JoinHandle>,
+    pub files: FileTable,
+    pub cwd: String,
+    /// Set by [`kill`] while the process runs, and acted on by
+    /// [`check_killed`] before it returns to user mode.
+    kill_pending: bool,
+    /// Set by [`wait`] for the thread that collects the exit status, which
+    /// keeps every other caller out.
+    waited: bool,
+}
+
+/// A snapshot of a process for listing.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: String,
+    pub state: ProcessState,
+    pub threads: usize,
+}
+
+/// Loads the ELF executable in `data` as a new child of the current
+/// process and starts it on a kernel thread of its own.
+pub fn spawn(name: &str, data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, SpawnError> {
+    let program = loader::load(data, argv, envp)?;
+    let parent = current();
+    let cwd = with_current(|process| process.cwd.clone()).unwrap_or_else(|| "/".to_string());
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let process = Process {
+        pid,
+        parent,
+        name: name.to_string(),
+        state: ProcessState::Ready,
+        address_space: Some(program.address_space),
+        threads: Vec::new(),
+        files: FileTable::with_console(),
+        cwd,
+        kill_pending: false,
+        waited: false,
+    };
+
+    // the table stays locked until the handle is in it, so that nobody can
+    // wait for the process without joining its thread
+    let (entry, stack_pointer) = (program.entry, program.stack_pointer);
+    let failed = {
+        let mut table = PROCESS_TABLE.lock();
+        table.insert(pid, process);
+        match thread::spawn("process", move || {
+            run(pid, entry, stack_pointer);
+        }) {
+            Ok(handle) => {
+                table.get_mut(&pid).unwrap().threads.push(handle);
+                None
+            }
+            Err(err) => Some((err, table.remove(&pid))),
+        }
+    };
+    if let Some((err, process)) = failed {
+        // freeing the page tables takes the frame allocator lock, so not under ours
+        drop(process);
+        return Err(err.into());
+    }
+    log::info!("spawned process {} ({})", pid, name);
+    Ok(pid)
+}
+
+/// Ends the current process with `code`. Called from the `exit` system call.
+///
+/// Panics if no process is running.
+pub fn exit(code: i32) -> ! {
+    user::leave(UserExit::Exited(code))
+}
+
+/// Waits for the child `pid` to exit, removes it from the process table and
+/// returns how it ended.
+///
+/// The calling thread blocks until the threads of the child have exited.
+pub fn wait(pid: Pid) -> Result<ExitStatus, ProcessError> {
+    let threads = {
+        let mut table = PROCESS_TABLE.lock();
+        let process = table.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        if process.parent != current() {
+            return Err(ProcessError::NotChild);
+        }
+        if process.waited {
+            return Err(ProcessError::AlreadyWaiting);
+        }
+        process.waited = true;
+        core::mem::take(&mut process.threads)
+    };
+    for thread in threads {
+        // the thread only ends after it recorded the exit status
+        thread.join().expect("a process thread is joined twice");
+    }
+
+    let process = PROCESS_TABLE.lock().remove(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    match process.state {
+        ProcessState::Exited(status) => Ok(status),
+        state => unreachable!("process {} {} after its threads exited", pid, state),
+    }
+}
+
+/// Kills `pid`. A process whose thread has not started yet ends right away,
+/// a running one the next time it would return to user mode, which is at
+/// the latest the next timer tick.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let address_space = {
+        let mut table = PROCESS_TABLE.lock();
+        let process = table.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        match process.state {
+            ProcessState::Ready => {
+                process.state = ProcessState::Exited(ExitStatus::Killed);
+                process.address_space.take()
+            }
+            ProcessState::Running => {
+                process.kill_pending = true;
+                None
+            }
+            ProcessState::Exited(_) => None,
+        }
+    };
+    // freeing the page tables takes the frame allocator lock, so not under ours
+    drop(address_space);
+    Ok(())
+}
+
+/// Returns the pid of the process whose user code is running, or
+/// [`KERNEL_PID`].
+pub fn current() -> Pid {
+    CURRENT.load(Ordering::SeqCst)
+}
+
+/// Makes `pid` the current process. Called when switching threads.
+pub(crate) fn set_current(pid: Pid) {
+    CURRENT.store(pid, Ordering::SeqCst);
+}
+
+/// Calls `f` with the current process, if there is one.
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let pid = current();
+    if pid == KERNEL_PID {
+        return None;
+    }
+    PROCESS_TABLE.lock().get_mut(&pid).map(f)
+}
+
+/// Ends the current process if it was killed. Called around every system
+/// call, and by the timer interrupt when it interrupted user mode.
+///
+/// Runs with interrupts disabled in the latter case, where a preempted
+/// thread may hold the process table lock. The check is then left to the
+/// next return to user mode instead.
+pub fn check_killed() {
+    let pid = current();
+    if pid == KERNEL_PID {
+        return;
+    }
+    let kill_pending = match PROCESS_TABLE.try_lock() {
+        Some(table) => table.get(&pid).map_or(false, |process| process.kill_pending),
+        None => false,
+    };
+    if kill_pending {
+        user::leave(UserExit::Killed);
+    }
+}
+
+/// Returns a snapshot of every process in the table.
+pub fn list() -> Vec<ProcessInfo> {
+    PROCESS_TABLE
+        .lock()
+        .values()
+        .map(|process| ProcessInfo {
+            pid: process.pid,
+            parent: process.parent,
+            name: process.name.clone(),
+            state: process.state,
+            threads: process.threads.len(),
+        })
+        .collect()
+}
+
+/// Runs the main thread of `pid` until the process exits, then records how
+/// it ended and frees its address space. Called on the kernel thread of the
+/// process; does nothing if the process was killed before it started.
+fn run(pid: Pid, entry: VirtAddr, stack_pointer: VirtAddr) -> Option<ExitStatus> {
+    {
+        let mut table = PROCESS_TABLE.lock();
+        let process = table.get_mut(&pid)?;
+        if process.state != ProcessState::Ready {
+            return None;
+        }
+        process.state = ProcessState::Running;
+        // the address space stays in the table, which only moves the
+        // `AddressSpace` itself and not the tables and regions it points to
+        unsafe { process.address_space.as_ref().unwrap().activate() };
+    }
+
+    let parent = CURRENT.swap(pid, Ordering::SeqCst);
+    let exit = unsafe { user::run(entry, stack_pointer) };
+    unsafe { address_space::activate_kernel() };
+    CURRENT.store(parent, Ordering::SeqCst);
+
+    let status = ExitStatus::from(exit);
+    log::info!("process {} ended: {:?}", pid, status);
+    let address_space = {
+        let mut table = PROCESS_TABLE.lock();
+        let process = table.get_mut(&pid).unwrap();
+        process.state = ProcessState::Exited(status);
+        // children of an exited process are handed to the kernel
+        for child in table.values_mut().filter(|child| child.parent == pid) {
+            child.parent = KERNEL_PID;
+        }
+        table.get_mut(&pid).unwrap().address_space.take()
+    };
+    drop(address_space);
+    Some(status)
+}
@@ -0,0 +1,38 @@
+# A test program for the loader and the system call interface. Prints a
+# greeting and exits with 0, or spins in user mode forever if it was given
+# any argument, to have something to kill.
+#
+# Built with:
+#   as hello.s -o hello.o
+#   ld -static -nostdlib -s -z noexecstack -z max-page-size=4096 hello.o -o hello
+
+    .intel_syntax noprefix
+
+    .text
+    .globl _start
+_start:
+    # write(1, message, message_len)
+    xor eax, eax
+    mov edi, 1
+    lea rsi, [rip + message]
+    mov edx, message_len
+    syscall
+
+    # argc, argv[0] is the program name
+    cmp qword ptr [rsp], 1
+    ja spin
+
+    # exit(0)
+    mov eax, 1
+    xor edi, edi
+    syscall
+    ud2
+
+spin:
+    pause
+    jmp spin
+
+    .section .rodata
+message:
+    .ascii "hello from user mode\n"
+    .set message_len, . - message
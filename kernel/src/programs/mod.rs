@@ -0,0 +1,15 @@
+/// A program linked into the kernel image, until there is a file system to
+/// load programs from.
+pub struct Program {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+pub const PROGRAMS: &[Program] = &[Program {
+    name: "hello",
+    data: include_bytes!("hello"),
+}];
+
+pub fn find(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
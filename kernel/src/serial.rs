@@ -40,6 +40,19 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Prints to the serial port even if `SERIAL1` is currently locked.
+///
+/// Breaks the lock of whoever was printing, so this must only be used on
+/// paths that never return to the interrupted code (panics, fatal faults).
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    x86_64::instructions::interrupts::disable();
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    let _ = SERIAL1.lock().write_fmt(args);
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
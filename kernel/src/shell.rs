@@ -2,14 +2,82 @@ const PROMPT: &'static str = "--> ";
 
 use x86_64::instructions::interrupts::without_interrupts;
 
+use alloc::vec::Vec;
+
 use crate::{
     framebuffer::{
         font_constants::{CHAR_RASTER_HEIGHT, CHAR_RASTER_WIDTH},
         FBWRITER,
     },
-    print,
+    memory::{self, vmm::KERNEL_VMM},
+    print, println,
 };
 
+/// A command that can be typed at the prompt.
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the available commands",
+        run: help,
+    },
+    Command {
+        name: "vmm",
+        help: "show the kernel virtual address space layout",
+        run: vmm,
+    },
+    Command {
+        name: "mem",
+        help: "show the boot memory map and memory usage",
+        run: mem,
+    },
+    Command {
+        name: "allocs",
+        help: "list live heap allocations (needs the debug-alloc feature)",
+        run: allocs,
+    },
+    Command {
+        name: "swap",
+        help: "swap on <KiB>: add a RAM disk swap device, swap test <KiB>: page through that much memory",
+        run: swap,
+    },
+    Command {
+        name: "ps",
+        help: "list processes",
+        run: ps,
+    },
+    Command {
+        name: "run",
+        help: "run <program> [args]: run a built-in program and wait for it to exit",
+        run: run,
+    },
+    Command {
+        name: "spawn",
+        help: "spawn <program> [args]: start a built-in program in the background",
+        run: spawn,
+    },
+    Command {
+        name: "wait",
+        help: "wait for the process with the given pid to exit",
+        run: wait,
+    },
+    Command {
+        name: "kill",
+        help: "kill the process with the given pid",
+        run: kill,
+    },
+    Command {
+        name: "threads",
+        help: "list kernel threads",
+        run: threads,
+    },
+];
+
 pub struct Shell {
     cursor_x: usize,
     cursor_y: usize,
@@ -50,3 +118,190 @@ impl Shell {
         });
     }
 }
+
+/// Runs a line typed at the prompt and prints a new prompt afterwards.
+pub fn run_command(line: &str) {
+    let mut words = line.split_whitespace();
+    if let Some(name) = words.next() {
+        let args: Vec<&str> = words.collect();
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(&args),
+            None => println!("unknown command: {} (try `help`)", name),
+        }
+    }
+    print!("{PROMPT}");
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("{:<8} {}", command.name, command.help);
+    }
+}
+
+fn vmm(_args: &[&str]) {
+    // copy the areas so the lock is not held while drawing to the screen
+    let areas: Vec<_> = KERNEL_VMM.try_get().unwrap().lock().iter().copied().collect();
+    for vma in areas {
+        println!("{}", vma);
+    }
+}
+
+fn mem(_args: &[&str]) {
+    for region in memory::memory_map() {
+        println!(
+            "{:#014x}-{:#014x} {:>10} KiB {:?}",
+            region.start,
+            region.end,
+            (region.end - region.start) / 1024,
+            region.kind
+        );
+    }
+
+    let stats = memory::stats();
+    println!("total:       {:>10} KiB", stats.total_bytes / 1024);
+    println!("usable:      {:>10} KiB", stats.usable_bytes / 1024);
+    println!("used:        {:>10} KiB", stats.used_bytes / 1024);
+    println!("page tables: {:>10} frames", stats.page_table_frames);
+    println!(
+        "heap:        {:>10} KiB used of {} KiB mapped, limit {} KiB, grown {} times",
+        stats.heap.used / 1024,
+        stats.heap.size / 1024,
+        stats.heap.limit / 1024,
+        stats.heap.grow_count
+    );
+    // allocate before taking the frame allocator lock, a growing heap needs it
+    let node_count = memory::FRAME_ALLOCATOR.try_get().unwrap().lock().nodes().len();
+    let mut nodes = Vec::with_capacity(node_count);
+    {
+        let frame_allocator = memory::FRAME_ALLOCATOR.try_get().unwrap().lock();
+        for node in frame_allocator.nodes().iter().take(node_count) {
+            nodes.push((node.id, node.free_frames, node.total_frames));
+        }
+    }
+    for (id, free_frames, total_frames) in nodes {
+        println!("node {:<6} {:>10} KiB free of {} KiB", id, free_frames * 4, total_frames * 4);
+    }
+    match memory::swap::stats() {
+        Some(swap) => println!(
+            "swap:        {:>10} KiB used of {} KiB",
+            (swap.total_slots - swap.free_slots) * 4,
+            swap.total_slots * 4
+        ),
+        None => println!("swap:        disabled"),
+    }
+}
+
+#[cfg(feature = "debug-alloc")]
+fn allocs(_args: &[&str]) {
+    let (allocations, untracked) = crate::allocator::live_allocations();
+    for allocation in &allocations {
+        print!("{:#018x} {:>8} bytes from", allocation.ptr, allocation.size);
+        for &caller in allocation.callers.iter().take_while(|&&caller| caller != 0) {
+            print!(" {:#x}", caller);
+        }
+        println!();
+    }
+    println!("{} live allocations, {} untracked", allocations.len(), untracked);
+}
+
+#[cfg(not(feature = "debug-alloc"))]
+fn allocs(_args: &[&str]) {
+    println!("allocation tracking is disabled, rebuild with the debug-alloc feature");
+}
+
+fn swap(args: &[&str]) {
+    let size = match args.get(1).map(|arg| arg.parse::<u64>()) {
+        Some(Ok(kib)) => kib * 1024,
+        _ => return println!("usage: swap on|test <KiB>"),
+    };
+    match args[0] {
+        "on" if memory::swap::stats().is_some() => println!("swap: already enabled"),
+        "on" => {
+            memory::swap::enable(alloc::boxed::Box::new(memory::swap::RamDisk::new(size as usize)));
+            println!("swap: {} KiB RAM disk enabled", size / 1024);
+        }
+        "test" => match memory::swap::pressure_test(size) {
+            Ok(swapped) => println!("swap: {} KiB checked, {} pages paged out", size / 1024, swapped),
+            Err(err) => println!("swap: test failed: {:?}", err),
+        },
+        _ => println!("usage: swap on|test <KiB>"),
+    }
+}
+
+fn ps(_args: &[&str]) {
+    println!("{:>5} {:>5} {:<12} {:>7} NAME", "PID", "PPID", "STATE", "THREADS");
+    for process in crate::process::list() {
+        println!(
+            "{:>5} {:>5} {:<12} {:>7} {}",
+            process.pid,
+            process.parent,
+            process.state,
+            process.threads,
+            process.name
+        );
+    }
+}
+
+/// Starts the built-in program named by `args[0]`, with `args` as its
+/// `argv`, and returns its pid.
+fn start_program(command: &str, args: &[&str]) -> Option<crate::process::Pid> {
+    let Some(&name) = args.first() else {
+        println!("usage: {} <program> [args]", command);
+        return None;
+    };
+    let Some(program) = crate::programs::find(name) else {
+        let names: Vec<_> = crate::programs::PROGRAMS.iter().map(|program| program.name).collect();
+        println!("{}: no program named {}, there is {}", command, name, names.join(", "));
+        return None;
+    };
+    match crate::process::spawn(program.name, program.data, args, &[]) {
+        Ok(pid) => Some(pid),
+        Err(err) => {
+            println!("{}: {:?}", command, err);
+            None
+        }
+    }
+}
+
+fn wait_for(pid: crate::process::Pid) {
+    match crate::process::wait(pid) {
+        Ok(status) => println!("process {} ended: {:?}", pid, status),
+        Err(err) => println!("wait: {:?}", err),
+    }
+}
+
+fn run(args: &[&str]) {
+    if let Some(pid) = start_program("run", args) {
+        wait_for(pid);
+    }
+}
+
+fn spawn(args: &[&str]) {
+    if let Some(pid) = start_program("spawn", args) {
+        println!("started process {}", pid);
+    }
+}
+
+fn wait(args: &[&str]) {
+    match args.first().map(|arg| arg.parse()) {
+        Some(Ok(pid)) => wait_for(pid),
+        _ => println!("usage: wait <pid>"),
+    }
+}
+
+fn kill(args: &[&str]) {
+    let pid = match args.first().map(|arg| arg.parse()) {
+        Some(Ok(pid)) => pid,
+        _ => return println!("usage: kill <pid>"),
+    };
+    if let Err(err) = crate::process::kill(pid) {
+        println!("kill: {:?}", err);
+    }
+}
+
+fn threads(_args: &[&str]) {
+    println!("{:>5} {:>5} {:<16} NAME", "TID", "PID", "STATE");
+    for thread in crate::thread::list() {
+        println!("{:>5} {:>5} {:<16} {}", thread.id, thread.pid, thread.state, thread.name);
+    }
+}
@@ -0,0 +1,107 @@
+use core::convert::TryInto;
+
+/// Size reserved for the symbol table. Must match `SYMBOL_TABLE_SIZE` in the
+/// build script.
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024; // 1 MiB
+const MAGIC: [u8; 8] = *b"IRONSYMS";
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 16;
+
+/// The kernel's own symbol table, used to name the functions of a backtrace.
+///
+/// The kernel reserves the `.ksymtab` section and the build script fills it
+/// in after linking, from the `.symtab` of the linked kernel. The layout, in
+/// little endian, is:
+///
+/// - the magic `IRONSYMS`
+/// - the link address of the section (`u64`), which gives the load offset
+/// - the number of symbols (`u32`) and the offset of the names (`u32`)
+/// - one `{ address: u64, size: u32, name: u32 }` entry per function, sorted
+///   by address, where `name` is relative to the names offset
+/// - the names, each a `u16` length followed by the demangled name
+///
+/// A kernel that was not patched has no symbols, and lookups return `None`.
+///
+/// It is mutable so that the compiler cannot assume it still holds the
+/// zeroes it was declared with.
+#[used]
+#[link_section = ".ksymtab"]
+static mut SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = {
+    let mut table = [0; SYMBOL_TABLE_SIZE];
+    let mut i = 0;
+    while i < MAGIC.len() {
+        table[i] = MAGIC[i];
+        i += 1;
+    }
+    table
+};
+
+/// A function that contains a looked up address.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    /// Runtime address of the start of the function.
+    pub address: u64,
+}
+
+fn table() -> &'static [u8] {
+    let table = core::hint::black_box(core::ptr::addr_of!(SYMBOL_TABLE));
+    unsafe { &*table }
+}
+
+fn u32_at(table: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(table.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+fn u64_at(table: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(table.get(offset..offset + 8)?.try_into().unwrap()))
+}
+
+/// Returns the number of symbols the build script stored.
+pub fn count() -> usize {
+    u32_at(table(), 16).unwrap_or(0) as usize
+}
+
+/// Finds the function containing the runtime address `address`.
+///
+/// This neither allocates nor takes locks, so it is safe to use from the
+/// panic and fatal exception paths.
+pub fn lookup(address: u64) -> Option<Symbol> {
+    let table = table();
+    if table[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let linked_at = u64_at(table, 8)?;
+    let load_offset = (table.as_ptr() as u64).wrapping_sub(linked_at);
+    let count = count();
+    let names = u32_at(table, 20)? as usize;
+    let target = address.wrapping_sub(load_offset);
+
+    let entry = |index: usize| {
+        let offset = HEADER_SIZE + index * ENTRY_SIZE;
+        Some((u64_at(table, offset)?, u32_at(table, offset + 8)?, u32_at(table, offset + 12)?))
+    };
+
+    // find the last symbol starting at or below the target
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle)?.0 <= target {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let (start, size, name) = entry(low.checked_sub(1)?)?;
+    if size != 0 && target >= start + u64::from(size) {
+        return None;
+    }
+
+    let name = names + name as usize;
+    let len = u16::from_le_bytes(table.get(name..name + 2)?.try_into().unwrap()) as usize;
+    let name = core::str::from_utf8(table.get(name + 2..name + 2 + len)?).ok()?;
+    Some(Symbol {
+        name,
+        address: start.wrapping_add(load_offset),
+    })
+}
@@ -0,0 +1,60 @@
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::thread::{self, PreemptGuard};
+
+/// A spin lock whose holder is not preempted.
+///
+/// The page fault handler and the heap run with interrupts disabled or from
+/// interrupt handlers, and take the locks of the memory manager. If the timer
+/// interrupt switched away from a thread holding one of them, those paths
+/// would spin on it with nothing left to release it.
+pub struct NoPreemptMutex<T> {
+    inner: Mutex<T>,
+}
+
+/// Unlocks the [`NoPreemptMutex`] and allows preemption again when dropped.
+pub struct NoPreemptMutexGuard<'a, T> {
+    // dropped first, so that a pending preemption happens after unlocking
+    guard: MutexGuard<'a, T>,
+    _preempt: PreemptGuard,
+}
+
+impl<T> NoPreemptMutex<T> {
+    pub const fn new(value: T) -> Self {
+        NoPreemptMutex {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> NoPreemptMutexGuard<'_, T> {
+        let preempt = thread::disable_preemption();
+        NoPreemptMutexGuard {
+            guard: self.inner.lock(),
+            _preempt: preempt,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<NoPreemptMutexGuard<'_, T>> {
+        let preempt = thread::disable_preemption();
+        self.inner.try_lock().map(|guard| NoPreemptMutexGuard {
+            guard,
+            _preempt: preempt,
+        })
+    }
+}
+
+impl<T> Deref for NoPreemptMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for NoPreemptMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
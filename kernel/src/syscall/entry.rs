@@ -0,0 +1,253 @@
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::cpu::gdt;
+use crate::memory::address_space::USER_MAP_END;
+use crate::memory::KernelStack;
+
+/// Size of the stack system calls run on until threads bring their own.
+const SYSCALL_STACK_SIZE: u64 = 4096 * 5;
+
+/// Top of the kernel stack the `syscall` entry switches to.
+static SYSCALL_KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+/// The user stack pointer, parked here by the `syscall` entry until the
+/// kernel stack is set up. Only a single processor enters it at a time.
+static SYSCALL_USER_RSP: AtomicU64 = AtomicU64::new(0);
+/// The user selectors, for returns that cannot use `sysretq`.
+static USER_CODE_SELECTOR: AtomicU64 = AtomicU64::new(0);
+static USER_DATA_SELECTOR: AtomicU64 = AtomicU64::new(0);
+
+/// The user register state saved on entry to a system call, in the order
+/// the entry stubs push it. Changes to it are restored on return.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// The system call number on entry, the result on return.
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn number(&self) -> u64 {
+        self.rax
+    }
+
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+// `syscall` leaves the user rip in rcx and rflags in r11, and does not switch
+// stacks. The entry builds an interrupt stack frame above the system call
+// frame, so that the return can fall back to `iretq`: `sysretq` with a
+// non-canonical rcx faults in ring 0 on the user stack, and on some
+// processors so does a return into the last user page.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {kernel_rsp}]",
+    "push qword ptr [rip + {user_data}]",
+    "push qword ptr [rip + {user_rsp}]",
+    "push r11",
+    "push qword ptr [rip + {user_code}]",
+    "push rcx",
+    "sub rsp, 8",
+    "push qword ptr [rip + {user_rsp}]",
+    "push r11",
+    "push rcx",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rcx",
+    "push rdx",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // a null frame pointer ends kernel backtraces here
+    "xor ebp, ebp",
+    "mov rdi, rsp",
+    "sti",
+    "call {dispatch}",
+    "cli",
+    "mov [rsp + 14 * 8], rax",
+    "mov rcx, [rsp + 15 * 8]",
+    "mov rax, {sysret_end}",
+    "cmp rcx, rax",
+    "jae 2f",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdx",
+    "pop rcx",
+    "pop rsi",
+    "pop rdi",
+    "pop rax",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "sysretq",
+    // return through the interrupt stack frame instead
+    "2:",
+    "mov [rsp + 19 * 8], rcx",
+    "mov rax, [rsp + 16 * 8]",
+    "mov [rsp + 21 * 8], rax",
+    "mov rax, [rsp + 17 * 8]",
+    "mov [rsp + 22 * 8], rax",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdx",
+    "pop rcx",
+    "pop rsi",
+    "pop rdi",
+    "pop rax",
+    "add rsp, 32",
+    "iretq",
+    user_rsp = sym SYSCALL_USER_RSP,
+    kernel_rsp = sym SYSCALL_KERNEL_RSP,
+    user_code = sym USER_CODE_SELECTOR,
+    user_data = sym USER_DATA_SELECTOR,
+    sysret_end = const USER_MAP_END,
+    dispatch = sym syscall_dispatch,
+);
+
+// The interrupt gate fallback builds the same frame below the interrupt stack
+// frame and copies rip, rflags and rsp back into it before `iretq`. The
+// padding keeps the stack 16 byte aligned at the call.
+global_asm!(
+    ".global syscall_interrupt_entry",
+    "syscall_interrupt_entry:",
+    "sub rsp, 8",
+    "push qword ptr [rsp + 8 + 24]",
+    "push qword ptr [rsp + 16 + 16]",
+    "push qword ptr [rsp + 24]",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rcx",
+    "push rdx",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "sti",
+    "call {dispatch}",
+    "cli",
+    "mov [rsp + 14 * 8], rax",
+    "mov rax, [rsp + 15 * 8]",
+    "mov [rsp + 19 * 8], rax",
+    "mov rax, [rsp + 16 * 8]",
+    "mov [rsp + 21 * 8], rax",
+    "mov rax, [rsp + 17 * 8]",
+    "mov [rsp + 22 * 8], rax",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdx",
+    "pop rcx",
+    "pop rsi",
+    "pop rdi",
+    "pop rax",
+    "add rsp, 32",
+    "iretq",
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_interrupt_entry();
+}
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) -> u64 {
+    super::dispatch(frame)
+}
+
+/// Returns the address of the entry stub for the interrupt gate fallback.
+pub(crate) fn interrupt_entry() -> VirtAddr {
+    VirtAddr::new(syscall_interrupt_entry as *const () as u64)
+}
+
+/// Sets the stack the next system call runs on.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    SYSCALL_KERNEL_RSP.store(stack_top.as_u64(), Ordering::SeqCst);
+}
+
+/// Points `syscall` at the entry stub and enables it.
+pub(super) fn init() {
+    let stack_top = KernelStack::new(SYSCALL_STACK_SIZE, "syscall stack")
+        .expect("failed to allocate the syscall stack")
+        .leak();
+    set_kernel_stack(stack_top);
+
+    let selectors = gdt::selectors();
+    USER_CODE_SELECTOR.store(u64::from(selectors.user_code_selector.0), Ordering::SeqCst);
+    USER_DATA_SELECTOR.store(u64::from(selectors.user_data_selector.0), Ordering::SeqCst);
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("the GDT layout does not fit sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // enter with interrupts off until the stack is switched, and with a
+    // clean direction flag
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
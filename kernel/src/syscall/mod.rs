@@ -0,0 +1,125 @@
+use crate::cpu::user;
+use crate::process::{self, File};
+
+mod entry;
+mod user_copy;
+pub(crate) use entry::interrupt_entry;
+pub use entry::{set_kernel_stack, SyscallFrame};
+pub(crate) use user_copy::exception_fixup;
+pub use user_copy::copy_from_user;
+
+// The system call number goes in `rax` and the arguments in `rdi`, `rsi`,
+// `rdx`, `r10`, `r8` and `r9`, like on Linux. The result comes back in `rax`,
+// with errors as negated [`Errno`] values. `syscall` clobbers `rcx` and `r11`.
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_TICKS: u64 = 2;
+const SYSCALL_COUNT: usize = 3;
+
+/// Longest buffer a single `write` copies.
+const MAX_WRITE: usize = 4096;
+
+/// Error numbers returned by system calls, negated in `rax`, with the same
+/// values as on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    /// Operation not permitted.
+    EPERM = 1,
+    /// No such file or directory.
+    ENOENT = 2,
+    /// No such process.
+    ESRCH = 3,
+    /// Input/output error.
+    EIO = 5,
+    /// Bad file descriptor.
+    EBADF = 9,
+    /// No child processes.
+    ECHILD = 10,
+    /// Resource temporarily unavailable.
+    EAGAIN = 11,
+    /// Out of memory.
+    ENOMEM = 12,
+    /// Bad address.
+    EFAULT = 14,
+    /// Invalid argument.
+    EINVAL = 22,
+    /// Function not implemented.
+    ENOSYS = 38,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// A system call handler, called with the six argument registers.
+type Handler = fn(&mut SyscallFrame, [u64; 6]) -> SyscallResult;
+
+/// The dispatch table, indexed by system call number.
+static SYSCALLS: [Option<Handler>; SYSCALL_COUNT] = {
+    let mut table: [Option<Handler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[SYS_WRITE as usize] = Some(sys_write);
+    table[SYS_EXIT as usize] = Some(sys_exit);
+    table[SYS_TICKS as usize] = Some(sys_ticks);
+    table
+};
+
+pub fn init() {
+    entry::init();
+    log::debug!("init'd syscalls");
+}
+
+/// Runs the system call in `frame` and returns the value for `rax`: the
+/// result, or the negated error number.
+fn dispatch(frame: &mut SyscallFrame) -> u64 {
+    crate::process::check_killed();
+    let number = frame.number();
+    let handler = usize::try_from(number)
+        .ok()
+        .and_then(|number| SYSCALLS.get(number).copied().flatten());
+    let result = match handler {
+        Some(handler) => handler(frame, frame.args()),
+        None => Err(Errno::ENOSYS),
+    };
+    crate::process::check_killed();
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+/// `write(fd, buf, len)`: writes up to `len` bytes of `buf` to the file
+/// `fd`, and returns how many were written.
+fn sys_write(_frame: &mut SyscallFrame, [fd, buf, len, ..]: [u64; 6]) -> SyscallResult {
+    // system calls made by the kernel itself write to the console
+    let file = process::with_current(|process| process.files.get(fd))
+        .unwrap_or_else(|| (fd == 1 || fd == 2).then_some(File::Console));
+    if file != Some(File::Console) {
+        return Err(Errno::EBADF);
+    }
+    let len = (len as usize).min(MAX_WRITE);
+    let mut buffer = [0; MAX_WRITE];
+    let bytes = &mut buffer[..len];
+    copy_from_user(bytes, buf)?;
+    // the console shows text, invalid UTF-8 is replaced rather than refused
+    for chunk in bytes.utf8_chunks() {
+        crate::print!("{}", chunk.valid());
+        crate::serial_print!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            crate::print!("{}", char::REPLACEMENT_CHARACTER);
+            crate::serial_print!("{}", char::REPLACEMENT_CHARACTER);
+        }
+    }
+    Ok(len as u64)
+}
+
+/// `exit(code)`: ends the calling process.
+fn sys_exit(_frame: &mut SyscallFrame, [code, ..]: [u64; 6]) -> SyscallResult {
+    if !user::is_running() {
+        return Err(Errno::ESRCH);
+    }
+    process::exit(code as i32)
+}
+
+/// `ticks()`: returns the number of timer ticks since boot.
+fn sys_ticks(_frame: &mut SyscallFrame, _args: [u64; 6]) -> SyscallResult {
+    Ok(crate::task::timer::ticks())
+}
@@ -0,0 +1,55 @@
+use core::arch::global_asm;
+
+use x86_64::VirtAddr;
+
+use super::Errno;
+use crate::memory::address_space;
+
+// copy_user(dst, src, len) -> bytes not copied
+//
+// A fault on the `rep movsb` resumes at `copy_user_fixup` through the
+// exception table below, with rcx still counting the bytes that are left.
+global_asm!(
+    ".global copy_user",
+    "copy_user:",
+    "mov rcx, rdx",
+    ".global copy_user_access",
+    "copy_user_access:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    ".global copy_user_fixup",
+    "copy_user_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn copy_user_access();
+    fn copy_user_fixup();
+}
+
+/// Returns where to resume kernel code that faulted at `rip` while touching
+/// user memory, or `None` if the fault is not expected.
+pub(crate) fn exception_fixup(rip: u64) -> Option<u64> {
+    // (faulting instruction, fixup) pairs
+    let table = [(copy_user_access as *const () as u64, copy_user_fixup as *const () as u64)];
+    table.iter().find(|&&(access, _)| access == rip).map(|&(_, fixup)| fixup)
+}
+
+/// Copies `dst.len()` bytes from user memory at `src` into `dst`.
+///
+/// Fails with `EFAULT` if the range is not user memory the process may
+/// read, or if reading it faults anyway, e.g. because another thread
+/// unmapped it.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    let start = VirtAddr::try_new(src).map_err(|_| Errno::EFAULT)?;
+    if !address_space::is_user_accessible(start, dst.len() as u64, false) {
+        return Err(Errno::EFAULT);
+    }
+    match unsafe { copy_user(dst.as_mut_ptr(), start.as_ptr(), dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
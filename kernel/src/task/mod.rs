@@ -1,4 +1,5 @@
 pub mod executor;
+pub mod timer;
 
 use core::{future::Future, pin::Pin};
 use alloc::boxed::Box;
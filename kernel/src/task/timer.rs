@@ -0,0 +1,80 @@
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+/// Number of intervals that can exist at the same time.
+const MAX_INTERVALS: usize = 8;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static WAKERS: [AtomicWaker; MAX_INTERVALS] = [const { AtomicWaker::new() }; MAX_INTERVALS];
+static SLOTS_USED: [AtomicBool; MAX_INTERVALS] = [const { AtomicBool::new(false) }; MAX_INTERVALS];
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    for waker in &WAKERS {
+        waker.wake();
+    }
+}
+
+/// A stream that yields the current tick count once every `period` timer
+/// ticks.
+pub struct Interval {
+    period: u64,
+    next: u64,
+    slot: usize,
+}
+
+impl Interval {
+    /// Creates an interval whose first tick is `period` ticks from now.
+    ///
+    /// Panics if [`MAX_INTERVALS`] intervals already exist.
+    pub fn new(period: u64) -> Self {
+        let slot = SLOTS_USED
+            .iter()
+            .position(|used| {
+                used.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+            })
+            .expect("too many timer intervals");
+        Interval {
+            period: period.max(1),
+            next: ticks() + period.max(1),
+            slot,
+        }
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u64>> {
+        let now = ticks();
+        if now >= self.next {
+            self.next = now + self.period;
+            return Poll::Ready(Some(now));
+        }
+        WAKERS[self.slot].register(context.waker());
+        let now = ticks();
+        if now >= self.next {
+            WAKERS[self.slot].take();
+            self.next = now + self.period;
+            return Poll::Ready(Some(now));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        WAKERS[self.slot].take();
+        SLOTS_USED[self.slot].store(false, Ordering::Release);
+    }
+}
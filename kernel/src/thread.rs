@@ -0,0 +1,590 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::cpu::fpu::FpuState;
+use crate::cpu::user::UserState;
+use crate::memory::address_space::ActiveSpace;
+use crate::memory::{KernelStack, MapError};
+use crate::process::{self, Pid, KERNEL_PID};
+use crate::task::timer;
+
+/// Most threads that can exist at the same time, including the boot and
+/// idle threads.
+const MAX_THREADS: usize = 64;
+const THREAD_STACK_SIZE: u64 = 4096 * 16;
+/// The part of a thread stack that is mapped up front. Below it, the stack
+/// is backed on demand.
+const THREAD_STACK_RESIDENT: u64 = 4096 * 4;
+
+pub type ThreadId = u64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Only ever locked with interrupts disabled, so the timer interrupt never
+/// finds it held by the thread it preempted.
+static SCHEDULER: OnceCell<Mutex<Scheduler>> = OnceCell::uninit();
+/// Number of live [`PreemptGuard`]s. While it is not zero, the timer
+/// interrupt leaves the current thread running.
+static PREEMPT_DISABLED: AtomicUsize = AtomicUsize::new(0);
+/// Set when a time slice ended while preemption was disabled.
+static PREEMPT_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Sleeping until the given tick.
+    Sleeping(u64),
+    /// Waiting for the thread with this id to exit.
+    Joining(ThreadId),
+    /// Finished, but not reaped yet.
+    Exited,
+}
+
+impl fmt::Display for ThreadState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThreadState::Ready => write!(f, "ready"),
+            ThreadState::Running => write!(f, "running"),
+            ThreadState::Sleeping(until) => write!(f, "sleeping({})", until),
+            ThreadState::Joining(id) => write!(f, "joining({})", id),
+            ThreadState::Exited => write!(f, "exited"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadError {
+    /// [`MAX_THREADS`] threads exist already.
+    TooManyThreads,
+    Stack(MapError),
+    NoSuchThread,
+    /// A thread cannot join itself.
+    WouldDeadlock,
+}
+
+impl From<MapError> for ThreadError {
+    fn from(err: MapError) -> Self {
+        ThreadError::Stack(err)
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    /// Stack pointer saved by `switch_context` while the thread is not
+    /// running, with the callee-saved registers on top.
+    saved_rsp: u64,
+    /// `None` for the boot thread, which runs on the stack the bootloader
+    /// handed over.
+    stack: Option<KernelStack>,
+    /// Taken by the thread when it first runs.
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// The rest of the processor state that belongs to a thread, saved when
+    /// it is switched out.
+    address_space: ActiveSpace,
+    user_state: UserState,
+    pid: Pid,
+    fpu: FpuState,
+    /// Set once the [`JoinHandle`] is dropped. Nobody waits for a detached
+    /// thread, so it is reaped as soon as it exits.
+    detached: bool,
+}
+
+/// A snapshot of a thread for listing.
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub pid: Pid,
+}
+
+/// A round robin scheduler. Its tables are allocated up front, because it
+/// runs in the timer interrupt, which must not take the heap lock.
+struct Scheduler {
+    threads: Vec<Option<Box<Thread>>>,
+    /// Slots of the ready threads, in the order they run. Never holds more
+    /// than [`MAX_THREADS`] entries, so it never grows.
+    ready: VecDeque<usize>,
+    current: usize,
+    /// Slot of the thread that runs when no other one is ready. It is never
+    /// queued.
+    idle: usize,
+}
+
+/// A switch from the current thread to another one, decided with the
+/// scheduler locked and made after unlocking it.
+struct Switch {
+    previous_rsp: *mut u64,
+    next_rsp: u64,
+    previous_fpu: *mut FpuState,
+    next_fpu: *const FpuState,
+    address_space: ActiveSpace,
+    user_state: UserState,
+    pid: Pid,
+}
+
+// switch_context(previous_rsp: *mut u64, next_rsp: u64)
+//
+// Saves the callee-saved registers on the current stack, stores the stack
+// pointer through `previous_rsp` and continues the thread whose stack pointer
+// is `next_rsp`, returning from its own call of `switch_context`. New threads
+// "return" into `thread_start`, which enables interrupts, since threads are
+// always switched with interrupts disabled.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global thread_start",
+    "thread_start:",
+    "sti",
+    "call {thread_main}",
+    "ud2",
+    thread_main = sym thread_main,
+);
+
+extern "C" {
+    fn switch_context(previous_rsp: *mut u64, next_rsp: u64);
+    fn thread_start();
+}
+
+impl Switch {
+    /// Saves the FPU registers of the current thread, restores the state of
+    /// the next thread and switches to it. Returns once the current thread
+    /// is switched back in.
+    ///
+    /// This function is unsafe because it must be called with interrupts
+    /// disabled and the scheduler unlocked, and only once per switch.
+    unsafe fn perform(self) {
+        (*self.previous_fpu).save();
+        (*self.next_fpu).restore();
+        self.address_space.restore();
+        self.user_state.restore();
+        process::set_current(self.pid);
+        switch_context(self.previous_rsp, self.next_rsp);
+    }
+}
+
+impl Scheduler {
+    fn thread(&mut self, slot: usize) -> &mut Thread {
+        self.threads[slot].as_deref_mut().expect("empty thread slot")
+    }
+
+    fn slot(&self, id: ThreadId) -> Option<usize> {
+        self.threads
+            .iter()
+            .position(|thread| thread.as_ref().map_or(false, |thread| thread.id == id))
+    }
+
+    fn make_ready(&mut self, slot: usize) {
+        self.thread(slot).state = ThreadState::Ready;
+        if slot != self.idle {
+            self.ready.push_back(slot);
+        }
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        for slot in 0..self.threads.len() {
+            if let Some(ThreadState::Sleeping(until)) = self.threads[slot].as_ref().map(|thread| thread.state) {
+                if until <= now {
+                    self.make_ready(slot);
+                }
+            }
+        }
+    }
+
+    /// Wakes the threads joining `id`.
+    fn wake_joiners(&mut self, id: ThreadId) {
+        for slot in 0..self.threads.len() {
+            if self.threads[slot].as_ref().map(|thread| thread.state) == Some(ThreadState::Joining(id)) {
+                self.make_ready(slot);
+            }
+        }
+    }
+
+    /// Picks the thread to run next. The current thread goes to the back of
+    /// the queue if it is still running, and otherwise stays off it until it
+    /// is woken up.
+    fn switch(&mut self) -> Option<Switch> {
+        let current = self.current;
+        if self.thread(current).state == ThreadState::Running {
+            self.make_ready(current);
+        }
+        let next = self.ready.pop_front().unwrap_or(self.idle);
+        self.thread(next).state = ThreadState::Running;
+        if next == current {
+            return None;
+        }
+        self.current = next;
+
+        let previous = self.thread(current);
+        previous.address_space = ActiveSpace::current();
+        previous.user_state = UserState::current();
+        previous.pid = process::current();
+        let previous_rsp: *mut u64 = &mut previous.saved_rsp;
+        let previous_fpu: *mut FpuState = &mut previous.fpu;
+        let next = self.thread(next);
+        Some(Switch {
+            previous_rsp,
+            next_rsp: next.saved_rsp,
+            previous_fpu,
+            next_fpu: &next.fpu,
+            address_space: next.address_space,
+            user_state: next.user_state,
+            pid: next.pid,
+        })
+    }
+}
+
+/// Runs `f` with the scheduler locked and interrupts disabled, then makes
+/// the switch it returns, if any.
+fn schedule<R>(f: impl FnOnce(&mut Scheduler) -> (R, Option<Switch>)) -> R {
+    interrupts::without_interrupts(|| {
+        let (result, switch) = f(&mut SCHEDULER.try_get().unwrap().lock());
+        if let Some(switch) = switch {
+            unsafe { switch.perform() };
+        }
+        result
+    })
+}
+
+/// Turns the code that is running into the boot thread, and creates the
+/// idle thread. Must be called on the stack the kernel keeps running on.
+pub fn init() {
+    let mut threads = Vec::with_capacity(MAX_THREADS);
+    threads.resize_with(MAX_THREADS, || None);
+    threads[0] = Some(Box::new(Thread {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name: "kernel",
+        state: ThreadState::Running,
+        saved_rsp: 0,
+        stack: None,
+        entry: None,
+        address_space: ActiveSpace::current(),
+        user_state: UserState::current(),
+        pid: KERNEL_PID,
+        fpu: FpuState::new(),
+        detached: true,
+    }));
+    SCHEDULER.init_once(|| {
+        Mutex::new(Scheduler {
+            threads,
+            ready: VecDeque::with_capacity(MAX_THREADS),
+            current: 0,
+            idle: 0,
+        })
+    });
+
+    let idle = spawn("idle", || loop {
+        x86_64::instructions::hlt();
+    })
+    .expect("failed to create the idle thread");
+    schedule(|scheduler| {
+        let slot = scheduler.slot(idle.id()).unwrap();
+        scheduler.ready.retain(|&queued| queued != slot);
+        scheduler.idle = slot;
+        ((), None)
+    });
+    log::debug!("init'd threads");
+}
+
+/// Starts a kernel thread named `name` that runs `f` on a stack of its own.
+///
+/// The thread is preempted by the timer interrupt like every other one, so
+/// it may loop without yielding.
+pub fn spawn<F>(name: &'static str, f: F) -> Result<JoinHandle, ThreadError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap_detached();
+
+    let stack = KernelStack::new_lazy(THREAD_STACK_SIZE, THREAD_STACK_RESIDENT, name)?;
+    // the initial frame `switch_context` pops: six zeroed registers, then
+    // `thread_start` as the return address, placed so that the stack is 16
+    // byte aligned at its call
+    let top = stack.top().as_u64();
+    let frame = (top - 9 * 8) as *mut u64;
+    unsafe {
+        core::ptr::write_bytes(frame, 0, 9);
+        frame.add(6).write(thread_start as *const () as u64);
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let thread = Box::new(Thread {
+        id,
+        name,
+        state: ThreadState::Ready,
+        saved_rsp: frame as u64,
+        stack: Some(stack),
+        entry: Some(Box::new(f)),
+        address_space: ActiveSpace::kernel(),
+        user_state: UserState::default(),
+        pid: KERNEL_PID,
+        fpu: FpuState::new(),
+        detached: false,
+    });
+    let result = schedule(|scheduler| {
+        let result = match scheduler.threads.iter().position(Option::is_none) {
+            Some(slot) => {
+                scheduler.threads[slot] = Some(thread);
+                scheduler.make_ready(slot);
+                Ok(())
+            }
+            None => Err(thread),
+        };
+        (result, None)
+    });
+    match result {
+        Ok(()) => Ok(JoinHandle { id }),
+        // the thread and its stack are freed with interrupts enabled
+        Err(_thread) => Err(ThreadError::TooManyThreads),
+    }
+}
+
+/// Gives the rest of the time slice to the next ready thread.
+pub fn yield_now() {
+    schedule(|scheduler| ((), scheduler.switch()));
+}
+
+/// Blocks the current thread for at least `ticks` timer ticks.
+pub fn sleep(ticks: u64) {
+    if ticks == 0 {
+        return yield_now();
+    }
+    let until = timer::ticks() + ticks;
+    schedule(|scheduler| {
+        let current = scheduler.current;
+        scheduler.thread(current).state = ThreadState::Sleeping(until);
+        ((), scheduler.switch())
+    });
+}
+
+/// Ends the current thread. Its stack is freed by whoever reaps it.
+///
+/// Panics if called on the boot or idle thread.
+pub fn exit() -> ! {
+    schedule(|scheduler| {
+        let current = scheduler.current;
+        assert!(
+            current != 0 && current != scheduler.idle,
+            "the {} thread cannot exit",
+            scheduler.thread(current).name
+        );
+        let id = scheduler.thread(current).id;
+        scheduler.thread(current).state = ThreadState::Exited;
+        scheduler.wake_joiners(id);
+        ((), scheduler.switch())
+    });
+    unreachable!("exited thread was switched back in");
+}
+
+/// Returns the id of the running thread.
+pub fn current() -> ThreadId {
+    schedule(|scheduler| {
+        let current = scheduler.current;
+        (scheduler.thread(current).id, None)
+    })
+}
+
+/// Returns a snapshot of every thread.
+pub fn list() -> Vec<ThreadInfo> {
+    // the scheduler must not wait for the heap lock, so allocate before
+    // locking it
+    let mut list = Vec::with_capacity(MAX_THREADS);
+    schedule(|scheduler| {
+        for (slot, thread) in scheduler.threads.iter().enumerate() {
+            let Some(thread) = thread else { continue };
+            // the saved pid of the running thread is stale
+            let pid = if slot == scheduler.current { process::current() } else { thread.pid };
+            list.push(ThreadInfo {
+                id: thread.id,
+                name: thread.name,
+                state: thread.state,
+                pid,
+            });
+        }
+        ((), None)
+    });
+    list
+}
+
+/// Checks that a thread that never yields cannot starve the others: sleeps
+/// a tick at a time while a spinning thread runs, and reports how long the
+/// sleeps took. Without working preemption, this never returns.
+pub fn check_preemption() {
+    const ROUNDS: u64 = 10;
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let spinner = match spawn("spin check", || {
+        while !STOP.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    }) {
+        Ok(spinner) => spinner,
+        Err(err) => return log::warn!("preemption check: failed to start a thread: {:?}", err),
+    };
+    let start = timer::ticks();
+    for _ in 0..ROUNDS {
+        sleep(1);
+    }
+    let elapsed = timer::ticks() - start;
+    STOP.store(true, Ordering::Relaxed);
+    let _ = spinner.join();
+
+    // each sleep should end within a tick or two of its deadline
+    if elapsed > ROUNDS * 3 {
+        log::error!("preemption check: {} one tick sleeps took {} ticks next to a spinning thread", ROUNDS, elapsed);
+    } else {
+        log::debug!("preemption check: {} one tick sleeps took {} ticks", ROUNDS, elapsed);
+    }
+}
+
+/// Keeps the timer interrupt from switching away from the current thread
+/// until the guard is dropped. The thread must not block in the meantime.
+pub fn disable_preemption() -> PreemptGuard {
+    PREEMPT_DISABLED.fetch_add(1, Ordering::SeqCst);
+    PreemptGuard { _not_send: PhantomData }
+}
+
+/// Returned by [`disable_preemption`]. Dropping the last one ends a time
+/// slice that ran out in the meantime.
+#[derive(Debug)]
+pub struct PreemptGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        if PREEMPT_DISABLED.fetch_sub(1, Ordering::SeqCst) == 1
+            && interrupts::are_enabled()
+            && PREEMPT_PENDING.swap(false, Ordering::SeqCst)
+        {
+            yield_now();
+        }
+    }
+}
+
+/// Called by the timer interrupt handler, after the end of interrupt, to
+/// wake the sleepers whose time is up and end the time slice of the current
+/// thread, unless preemption is disabled.
+pub(crate) fn preempt() {
+    let Ok(scheduler) = SCHEDULER.try_get() else {
+        return;
+    };
+    if PREEMPT_DISABLED.load(Ordering::SeqCst) != 0 {
+        PREEMPT_PENDING.store(true, Ordering::SeqCst);
+        return;
+    }
+    // interrupts are disabled here, so the lock is only ever held if the
+    // interrupt arrived in the middle of a switch
+    let switch = match scheduler.try_lock() {
+        Some(mut scheduler) => {
+            scheduler.wake_sleepers(timer::ticks());
+            scheduler.switch()
+        }
+        None => None,
+    };
+    if let Some(switch) = switch {
+        unsafe { switch.perform() };
+    }
+}
+
+/// Frees the stacks of detached threads that exited. One at a time, because
+/// dropping a thread takes the heap lock, which the scheduler must not hold.
+fn reap_detached() {
+    while let Some(thread) = schedule(|scheduler| {
+        let slot = scheduler.threads.iter().position(|thread| {
+            thread
+                .as_ref()
+                .map_or(false, |thread| thread.detached && thread.state == ThreadState::Exited)
+        });
+        (slot.and_then(|slot| scheduler.threads[slot].take()), None)
+    }) {
+        drop(thread);
+    }
+}
+
+extern "C" fn thread_main() -> ! {
+    let entry = schedule(|scheduler| {
+        let current = scheduler.current;
+        (scheduler.thread(current).entry.take(), None)
+    });
+    entry.expect("thread started twice")();
+    exit()
+}
+
+/// Owns a thread until it is joined. Dropping it detaches the thread.
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread exits, then frees it.
+    pub fn join(self) -> Result<(), ThreadError> {
+        let id = self.id;
+        core::mem::forget(self);
+        let thread = schedule(|scheduler| {
+            let slot = match scheduler.slot(id) {
+                Some(slot) => slot,
+                None => return (Err(ThreadError::NoSuchThread), None),
+            };
+            if slot == scheduler.current {
+                return (Err(ThreadError::WouldDeadlock), None);
+            }
+            if scheduler.thread(slot).state == ThreadState::Exited {
+                return (Ok(scheduler.threads[slot].take()), None);
+            }
+            let current = scheduler.current;
+            scheduler.thread(current).state = ThreadState::Joining(id);
+            (Ok(None), scheduler.switch())
+        })?;
+        match thread {
+            Some(thread) => drop(thread),
+            // woken up by the thread exiting
+            None => return JoinHandle { id }.join(),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let id = self.id;
+        schedule(|scheduler| {
+            if let Some(slot) = scheduler.slot(id) {
+                scheduler.thread(slot).detached = true;
+            }
+            ((), None)
+        });
+    }
+}
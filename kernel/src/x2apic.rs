@@ -4,14 +4,25 @@ use spin::Mutex;
 use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
 use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder, TimerDivide};
 use x86_64::instructions::port::Port;
-use x86_64::VirtAddr;
+use x86_64::PhysAddr;
 
 use crate::cpu::interrupts::InterruptIndex;
+use crate::memory::{map_mmio, CacheMode, MmioRegion};
 use crate::{hlt_loop, println};
 
 pub static LAPIC: OnceCell<Mutex<LocalApic>> = OnceCell::uninit();
 pub static IOAPIC: OnceCell<Mutex<IoApic>> = OnceCell::uninit();
 
+/// The register mappings of the local APIC and the I/O APIC, kept alive for
+/// as long as the kernel runs.
+static LAPIC_MMIO: OnceCell<MmioRegion> = OnceCell::uninit();
+static IOAPIC_MMIO: OnceCell<MmioRegion> = OnceCell::uninit();
+
+/// Size of the local APIC register page.
+const LAPIC_MMIO_SIZE: usize = 0x1000;
+/// Size of the I/O APIC register window (IOREGSEL and IOWIN).
+const IOAPIC_MMIO_SIZE: usize = 0x20;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum IrqVector {
@@ -33,9 +44,10 @@ pub fn init(apic: &Apic) {
 }
 
 pub fn init_lapic(apic: &Apic) {
-    let apic_phys_addr = apic.local_apic_address;
-    let apic_virt_addr = crate::memory::PHYS_MEM_OFFSET.get().unwrap().as_u64() + apic_phys_addr;
-    crate::map_physical_to_virtual!(apic_phys_addr, apic_virt_addr);
+    let apic_phys_addr = PhysAddr::new(apic.local_apic_address);
+    let region = map_mmio(apic_phys_addr, LAPIC_MMIO_SIZE, CacheMode::Uncached)
+        .expect("failed to map local APIC registers");
+    let apic_virt_addr = LAPIC_MMIO.get_or_init(|| region).virt_addr().as_u64();
 
     log::trace!("mapped phys addr to virt addr");
 
@@ -65,10 +77,10 @@ pub fn init_lapic(apic: &Apic) {
 }
 
 unsafe fn init_ioapic(apic: &Apic) {
-    let physical_address = apic.io_apics.get(0).unwrap().address as u64;
-    let phys_mem_offset = crate::memory::PHYS_MEM_OFFSET.try_get().unwrap();
-    let virtual_address = phys_mem_offset.as_u64() + physical_address;
-    crate::map_physical_to_virtual!(physical_address, virtual_address);
+    let physical_address = PhysAddr::new(apic.io_apics.get(0).unwrap().address as u64);
+    let region = map_mmio(physical_address, IOAPIC_MMIO_SIZE, CacheMode::Uncached)
+        .expect("failed to map I/O APIC registers");
+    let virtual_address = IOAPIC_MMIO.get_or_init(|| region).virt_addr().as_u64();
 
     let mut ioapic = IoApic::new(virtual_address);
     ioapic.init(crate::cpu::interrupts::IOAPIC_INTERRUPT_INDEX_OFFSET);